@@ -0,0 +1,5 @@
+CREATE TABLE login_tokens (
+	token	text PRIMARY KEY,
+	user_id	uuid NOT NULL REFERENCES users(id),
+	expires	timestamp NOT NULL
+);
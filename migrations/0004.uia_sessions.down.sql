@@ -0,0 +1 @@
+DROP TABLE uia_sessions;
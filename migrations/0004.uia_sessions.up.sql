@@ -0,0 +1,6 @@
+CREATE TABLE uia_sessions (
+	id		text PRIMARY KEY,
+	user_id	uuid REFERENCES users(id) ON DELETE CASCADE,
+	created	timestamp NOT NULL,
+	completed	text[] NOT NULL
+);
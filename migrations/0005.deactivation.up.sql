@@ -0,0 +1,3 @@
+ALTER TABLE users
+	ADD COLUMN deactivated	boolean NOT NULL DEFAULT false,
+	ADD COLUMN erased	boolean NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE users ADD CONSTRAINT users_localpart_key UNIQUE (localpart);
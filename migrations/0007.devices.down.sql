@@ -0,0 +1,4 @@
+ALTER TABLE tokens
+	DROP CONSTRAINT tokens_device_fkey,
+	ALTER COLUMN user_id DROP NOT NULL;
+DROP TABLE devices;
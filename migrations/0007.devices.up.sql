@@ -0,0 +1,17 @@
+CREATE TABLE devices (
+	user_id	uuid NOT NULL REFERENCES users(id),
+	id		text NOT NULL,
+	display_name	text,
+	last_seen_ip	text,
+	last_seen_ts	timestamp,
+	CONSTRAINT devices_pkey PRIMARY KEY(user_id, id)
+);
+
+INSERT INTO devices (user_id, id)
+	SELECT DISTINCT user_id, device_id FROM tokens WHERE user_id IS NOT NULL;
+DELETE FROM tokens WHERE user_id IS NULL;
+
+ALTER TABLE tokens
+	ALTER COLUMN user_id SET NOT NULL,
+	ADD CONSTRAINT tokens_device_fkey FOREIGN KEY (user_id, device_id)
+		REFERENCES devices(user_id, id) ON DELETE CASCADE;
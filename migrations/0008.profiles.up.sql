@@ -0,0 +1,7 @@
+CREATE TABLE profiles (
+	user_id	uuid PRIMARY KEY REFERENCES users(id),
+	displayname	text,
+	avatar_url	text
+);
+
+INSERT INTO profiles (user_id, displayname) SELECT id, localpart FROM users;
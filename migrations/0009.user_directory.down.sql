@@ -0,0 +1,2 @@
+DROP INDEX profiles_displayname_search_idx;
+DROP INDEX users_localpart_search_idx;
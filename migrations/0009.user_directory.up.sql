@@ -0,0 +1,4 @@
+CREATE INDEX users_localpart_search_idx ON users
+	USING gin (to_tsvector('simple', regexp_replace(localpart, '[^a-z0-9]+', ' ', 'g')));
+CREATE INDEX profiles_displayname_search_idx ON profiles
+	USING gin (to_tsvector('simple', coalesce(displayname, '')));
@@ -0,0 +1,8 @@
+CREATE TABLE account_data (
+	user_id	uuid NOT NULL REFERENCES users(id),
+	-- Empty for global account data.
+	room_id	text NOT NULL DEFAULT '',
+	type	text NOT NULL,
+	content	jsonb NOT NULL,
+	PRIMARY KEY (user_id, room_id, type)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE rooms (
+	id		text PRIMARY KEY,
+	version	text NOT NULL,
+	creator	uuid NOT NULL REFERENCES users(id),
+	is_public	boolean NOT NULL DEFAULT false,
+	created	timestamp NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE events (
+	id		text PRIMARY KEY,
+	room_id	text NOT NULL REFERENCES rooms(id),
+	type		text NOT NULL,
+	state_key	text,
+	sender	text NOT NULL,
+	origin_server_ts	bigint NOT NULL,
+	content	jsonb NOT NULL
+);
+
+CREATE INDEX events_room_id_idx ON events (room_id);
@@ -0,0 +1,10 @@
+DROP TABLE current_state;
+
+DROP INDEX events_room_stream_idx;
+CREATE INDEX events_room_id_idx ON events (room_id);
+
+ALTER TABLE events
+	DROP COLUMN stream_ordering,
+	DROP COLUMN depth,
+	DROP COLUMN prev_events,
+	DROP COLUMN auth_events;
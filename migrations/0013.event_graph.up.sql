@@ -0,0 +1,40 @@
+ALTER TABLE events
+	ADD COLUMN stream_ordering	bigserial UNIQUE,
+	ADD COLUMN depth	bigint,
+	ADD COLUMN prev_events	text[],
+	ADD COLUMN auth_events	text[] NOT NULL DEFAULT '{}';
+
+-- Existing rooms were only ever appended to in order, so their graph is linear.
+UPDATE events SET depth = linear.depth, prev_events = linear.prev_events
+	FROM (SELECT id,
+			row_number() OVER room AS depth,
+			array_remove(ARRAY[lag(id) OVER room], NULL) AS prev_events
+		FROM events WINDOW room AS (PARTITION BY room_id ORDER BY stream_ordering)) AS linear
+	WHERE events.id = linear.id;
+
+ALTER TABLE events
+	ALTER COLUMN depth SET NOT NULL,
+	ALTER COLUMN prev_events SET NOT NULL,
+	ALTER COLUMN auth_events DROP DEFAULT;
+
+DROP INDEX events_room_id_idx;
+CREATE INDEX events_room_stream_idx ON events (room_id, stream_ordering);
+
+CREATE TABLE current_state (
+	room_id	text NOT NULL REFERENCES rooms(id),
+	type		text NOT NULL,
+	state_key	text NOT NULL,
+	event_id	text NOT NULL REFERENCES events(id),
+	-- The membership of m.room.member events, so that rooms and members can be looked up directly.
+	membership	text,
+	CONSTRAINT current_state_pkey PRIMARY KEY(room_id, type, state_key)
+);
+
+CREATE INDEX current_state_member_idx ON current_state (state_key, membership)
+	WHERE type = 'm.room.member';
+
+INSERT INTO current_state (room_id, type, state_key, event_id, membership)
+	SELECT DISTINCT ON (room_id, type, state_key) room_id, type, state_key, id,
+		CASE WHEN type = 'm.room.member' THEN content->>'membership' END
+	FROM events WHERE state_key IS NOT NULL
+	ORDER BY room_id, type, state_key, stream_ordering DESC;
@@ -0,0 +1,6 @@
+CREATE TABLE event_transactions (
+	token_id	uuid NOT NULL REFERENCES tokens(id) ON DELETE CASCADE,
+	txn_id	text NOT NULL,
+	event_id	text NOT NULL REFERENCES events(id),
+	CONSTRAINT event_transactions_pkey PRIMARY KEY(token_id, txn_id)
+);
@@ -0,0 +1,2 @@
+-- The event redacted by an m.room.redaction event, in rooms which keep it outside of the content.
+ALTER TABLE events ADD COLUMN redacts	text;
@@ -0,0 +1,7 @@
+CREATE TABLE forgotten_rooms (
+	user_id	uuid NOT NULL REFERENCES users(id),
+	room_id	text NOT NULL REFERENCES rooms(id),
+	-- The membership event which was forgotten; any later one brings the room back.
+	event_id	text NOT NULL REFERENCES events(id),
+	PRIMARY KEY (user_id, room_id)
+);
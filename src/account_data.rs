@@ -0,0 +1,285 @@
+use futures::{future, Future};
+use hyper::{Body, Request};
+use serde_json::json;
+
+use crate::authentication::AuthContext;
+use crate::db;
+use crate::user_id::UserId;
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+// Global account data is stored with an empty room ID.
+const ACCOUNT_DATA_QUERY: &str =
+    "SELECT content FROM account_data WHERE user_id=$1 AND room_id=$2 AND type=$3";
+
+const SET_ACCOUNT_DATA_QUERY: &str = "INSERT INTO account_data (user_id, room_id, type, content) \
+                                      VALUES ($1, $2, $3, $4) \
+                                      ON CONFLICT (user_id, room_id, type) \
+                                      DO UPDATE SET content=EXCLUDED.content";
+
+const ACCOUNT_DATA_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "Account data not found");
+const NOT_OWN_ACCOUNT_DATA: ErrorBody = ErrorBody::new_static(
+    error_code::M_FORBIDDEN,
+    "Cannot access the account data of another user",
+);
+const INVALID_ROOM_ID: ErrorBody =
+    ErrorBody::new_static(error_code::M_INVALID_PARAM, "Invalid room ID");
+
+/// Checks that the account data being accessed belongs to the caller, resolving the optional
+/// room ID to the key it is stored under.
+pub fn check_owner(
+    server_name: &str,
+    auth: &AuthContext,
+    user_id: &str,
+    room_id: Option<String>,
+) -> Result<String, ErrorBody> {
+    if UserId::parse(user_id)? != UserId::new(&auth.localpart, server_name) {
+        return Err(NOT_OWN_ACCOUNT_DATA);
+    }
+
+    match room_id {
+        Some(ref room_id) if !room_id.starts_with('!') => Err(INVALID_ROOM_ID),
+        Some(room_id) => Ok(room_id),
+        None => Ok(String::new()),
+    }
+}
+
+/// Returns an account data event of the calling user, either global or for a single room.
+pub fn get_account_data(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    user_id: String,
+    room_id: Option<String>,
+    type_: String,
+) -> EndpointFutureBox {
+    let room_id = match check_owner(&server.server_name, &auth, &user_id, room_id) {
+        Ok(room_id) => room_id,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| {
+                db::query_opt(
+                    db,
+                    ACCOUNT_DATA_QUERY,
+                    params![auth.user_id, room_id, type_],
+                )
+            })
+            .map_err(crate::Error::from)
+            .and_then(|row| {
+                let content: serde_json::Value = row.ok_or(ACCOUNT_DATA_NOT_FOUND)?.get(0);
+                Ok(json_response(content))
+            }),
+    )
+}
+
+/// Replaces an account data event of the calling user, either global or for a single room.
+pub fn set_account_data(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    user_id: String,
+    room_id: Option<String>,
+    type_: String,
+) -> EndpointFutureBox {
+    let room_id = match check_owner(&server.server_name, &auth, &user_id, room_id) {
+        Ok(room_id) => room_id,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    let db_pool = server.db_pool.clone();
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |content: serde_json::Map<String, serde_json::Value>| {
+                db_pool
+                    .run(move |db| {
+                        db::execute(
+                            db,
+                            SET_ACCOUNT_DATA_QUERY,
+                            params![
+                                auth.user_id,
+                                room_id,
+                                type_,
+                                serde_json::Value::Object(content)
+                            ],
+                        )
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+const TAGS_QUERY: &str = "SELECT content->'tags' FROM account_data \
+                          WHERE user_id=$1 AND room_id=$2 AND type='m.tag'";
+
+// Tags are kept as room account data, so that they can also be read and written as a whole.
+const SET_TAG_QUERY: &str = "INSERT INTO account_data (user_id, room_id, type, content) \
+     VALUES ($1, $2, 'm.tag', jsonb_build_object('tags', jsonb_build_object($3::text, $4::jsonb))) \
+     ON CONFLICT (user_id, room_id, type) \
+     DO UPDATE SET content = account_data.content || jsonb_build_object('tags', \
+     CASE WHEN jsonb_typeof(account_data.content->'tags') = 'object' \
+     THEN account_data.content->'tags' ELSE '{}' END \
+     || jsonb_build_object($3::text, $4::jsonb))";
+
+const DELETE_TAG_QUERY: &str = "UPDATE account_data \
+     SET content = jsonb_set(content, '{tags}', (content->'tags') - $3::text) \
+     WHERE user_id=$1 AND room_id=$2 AND type='m.tag' \
+     AND jsonb_typeof(content->'tags') = 'object'";
+
+const INVALID_TAG_ORDER: ErrorBody =
+    ErrorBody::new_static(error_code::M_INVALID_PARAM, "Tag order must be a number");
+
+/// Tags may carry any content, but their `order` has to be a number if it is given.
+fn check_tag_content(
+    content: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), ErrorBody> {
+    match content.get("order") {
+        None | Some(serde_json::Value::Number(_)) => Ok(()),
+        Some(_) => Err(INVALID_TAG_ORDER),
+    }
+}
+
+/// Lists the tags the calling user has given to a room.
+pub fn get_tags(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    user_id: String,
+    room_id: String,
+) -> EndpointFutureBox {
+    let room_id = match check_owner(&server.server_name, &auth, &user_id, Some(room_id)) {
+        Ok(room_id) => room_id,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::query_opt(db, TAGS_QUERY, params![auth.user_id, room_id]))
+            .map_err(crate::Error::from)
+            .map(|row| {
+                let tags = match row.and_then(|row| row.get::<_, Option<serde_json::Value>>(0)) {
+                    Some(tags @ serde_json::Value::Object(_)) => tags,
+                    _ => json!({}),
+                };
+                json_response(json!({ "tags": tags }))
+            }),
+    )
+}
+
+/// Adds a tag to a room, replacing its content if the room already has it.
+pub fn set_tag(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    user_id: String,
+    room_id: String,
+    tag: String,
+) -> EndpointFutureBox {
+    let room_id = match check_owner(&server.server_name, &auth, &user_id, Some(room_id)) {
+        Ok(room_id) => room_id,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    let db_pool = server.db_pool.clone();
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(|content: serde_json::Map<String, serde_json::Value>| {
+                check_tag_content(&content)?;
+                Ok(content)
+            })
+            .and_then(move |content| {
+                db_pool
+                    .run(move |db| {
+                        db::execute(
+                            db,
+                            SET_TAG_QUERY,
+                            params![
+                                auth.user_id,
+                                room_id,
+                                tag,
+                                serde_json::Value::Object(content)
+                            ],
+                        )
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+/// Removes a tag from a room. Removing a tag the room does not have is not an error.
+pub fn delete_tag(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    user_id: String,
+    room_id: String,
+    tag: String,
+) -> EndpointFutureBox {
+    let room_id = match check_owner(&server.server_name, &auth, &user_id, Some(room_id)) {
+        Ok(room_id) => room_id,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::execute(db, DELETE_TAG_QUERY, params![auth.user_id, room_id, tag]))
+            .map_err(crate::Error::from)
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_owner, check_tag_content};
+    use crate::authentication::AuthContext;
+    use crate::error_code;
+    use serde_json::json;
+
+    fn alice() -> AuthContext {
+        AuthContext {
+            token_id: uuid::Uuid::nil(),
+            user_id: uuid::Uuid::nil(),
+            localpart: "alice".to_owned(),
+            device_id: "DEVICE".to_owned(),
+        }
+    }
+
+    #[test]
+    fn owners() {
+        let owner = |user_id, room_id: Option<&str>| {
+            check_owner("example.com", &alice(), user_id, room_id.map(str::to_owned))
+        };
+
+        assert_eq!(owner("@alice:example.com", None).unwrap(), "");
+        assert_eq!(
+            owner("@alice:example.com", Some("!room:example.com")).unwrap(),
+            "!room:example.com"
+        );
+
+        let errcode = |user_id, room_id| owner(user_id, room_id).unwrap_err().errcode;
+        assert_eq!(errcode("@bob:example.com", None), error_code::M_FORBIDDEN);
+        assert_eq!(errcode("@alice:example.org", None), error_code::M_FORBIDDEN);
+        assert_eq!(errcode("alice", None), error_code::M_INVALID_PARAM);
+        assert_eq!(
+            errcode("@alice:example.com", Some("#alias:example.com")),
+            error_code::M_INVALID_PARAM
+        );
+    }
+
+    #[test]
+    fn tag_contents() {
+        let check = |content: serde_json::Value| check_tag_content(content.as_object().unwrap());
+        assert!(check(json!({})).is_ok());
+        assert!(check(json!({ "order": 0.25 })).is_ok());
+        assert!(check(json!({ "order": 1, "colour": "red" })).is_ok());
+        assert!(check(json!({ "order": "0.25" })).is_err());
+        assert!(check(json!({ "order": null })).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use futures::{Future, IntoFuture, Stream};
+use hyper::{Body, Request};
+use std::net::IpAddr;
+
+use crate::db;
+use crate::{error_code, tack_on, DbPool, ErrorBody};
+
+const TOKEN_QUERY: &str = "SELECT tokens.user_id, tokens.device_id, users.localpart FROM tokens \
+                           INNER JOIN users ON users.id = tokens.user_id WHERE tokens.id = $1";
+
+const LAST_SEEN_QUERY: &str = "UPDATE devices SET last_seen_ip=$3, last_seen_ts=localtimestamp \
+                               WHERE user_id=$1 AND id=$2";
+
+const MISSING_TOKEN: ErrorBody =
+    ErrorBody::new_static(error_code::M_MISSING_TOKEN, "Missing access token");
+const UNKNOWN_TOKEN: ErrorBody =
+    ErrorBody::new_static(error_code::M_UNKNOWN_TOKEN, "Unrecognised access token");
+
+/// The user and device that an access token was issued to.
+#[derive(Clone, Debug)]
+pub struct AuthContext {
+    pub token_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub localpart: String,
+    pub device_id: String,
+}
+
+/// Extracts the access token from either the `Authorization: Bearer` header or the
+/// `access_token` query parameter.
+fn access_token(req: &Request<Body>) -> Option<String> {
+    if let Some(value) = req.headers().get(hyper::header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        let mut parts = value.splitn(2, ' ');
+        return match (parts.next(), parts.next()) {
+            (Some("Bearer"), Some(token)) => Some(token.trim().to_owned()),
+            _ => None,
+        };
+    }
+
+    qstring::QString::from(req.uri().query().unwrap_or(""))
+        .get("access_token")
+        .map(|token| token.to_owned())
+}
+
+/// Looks up the user and device owning the access token presented with `req`, recording that the
+/// device was last seen at `remote_ip`.
+pub fn authenticate(
+    db_pool: &DbPool,
+    req: &Request<Body>,
+    remote_ip: IpAddr,
+) -> impl Future<Item = AuthContext, Error = crate::Error> {
+    let db_pool = db_pool.clone();
+
+    access_token(req)
+        .ok_or(MISSING_TOKEN)
+        .and_then(|token| uuid::Uuid::parse_str(&token).map_err(|_| UNKNOWN_TOKEN))
+        .map_err(crate::Error::from)
+        .into_future()
+        .and_then(move |token_id| {
+            db_pool
+                .run(move |mut db| {
+                    db.prepare(TOKEN_QUERY)
+                        .then(|res| tack_on(res, db))
+                        .and_then(move |(q, mut db)| {
+                            db.query(&q, &[&token_id])
+                                .into_future()
+                                .map(|(row, _)| row)
+                                .map_err(|(err, _)| err)
+                                .then(|res| tack_on(res, db))
+                        })
+                })
+                .map_err(crate::Error::from)
+                .and_then(move |row| {
+                    let row = row.ok_or(UNKNOWN_TOKEN)?;
+
+                    Ok(AuthContext {
+                        token_id,
+                        user_id: row.get(0),
+                        device_id: row.get(1),
+                        localpart: row.get(2),
+                    })
+                })
+                .and_then(move |auth| {
+                    db_pool
+                        .run({
+                            let params = params![
+                                auth.user_id,
+                                auth.device_id.clone(),
+                                remote_ip.to_string()
+                            ];
+                            move |db| db::execute(db, LAST_SEEN_QUERY, params)
+                        })
+                        .map_err(crate::Error::from)
+                        .map(move |_| auth)
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request};
+
+    use super::access_token;
+
+    #[test]
+    fn access_token_sources() {
+        let req = Request::get("/_matrix/client/r0/logout")
+            .header("Authorization", "Bearer abc")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&req).as_deref(), Some("abc"));
+
+        let req = Request::get("/_matrix/client/r0/logout?access_token=def")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&req).as_deref(), Some("def"));
+
+        let req = Request::get("/_matrix/client/r0/logout")
+            .header("Authorization", "Basic abc")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(access_token(&req), None);
+    }
+}
@@ -0,0 +1,105 @@
+use futures::{Future, IntoFuture, Stream};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row};
+
+use crate::tack_on;
+
+/// Owned query parameters, so that they can outlive the statement being prepared.
+pub type Params = Vec<Box<dyn ToSql + Send>>;
+
+/// A boxed step of a `transaction`, for when different branches run different queries.
+pub type TransactionFutureBox<T> =
+    Box<dyn Future<Item = (T, Client), Error = (crate::Error, Client)> + Send>;
+
+/// Builds a `Params` list from a series of values.
+macro_rules! params {
+    ($($param:expr),* $(,)*) => {
+        vec![$(Box::new($param) as Box<dyn tokio_postgres::types::ToSql + Send>),*]
+    };
+}
+
+fn param_refs(params: &Params) -> Vec<&dyn ToSql> {
+    params.iter().map(|param| &**param as &dyn ToSql).collect()
+}
+
+/// Prepares and executes `query`, resolving to the number of rows modified.
+pub fn execute(
+    mut db: Client,
+    query: &str,
+    params: Params,
+) -> impl Future<Item = (u64, Client), Error = (tokio_postgres::Error, Client)> {
+    db.prepare(query)
+        .then(|res| tack_on(res, db))
+        .and_then(move |(q, mut db)| {
+            db.execute(&q, &param_refs(&params))
+                .then(|res| tack_on(res, db))
+        })
+}
+
+/// Prepares and runs `query`, resolving to every row returned.
+pub fn query(
+    mut db: Client,
+    query: &str,
+    params: Params,
+) -> impl Future<Item = (Vec<Row>, Client), Error = (tokio_postgres::Error, Client)> {
+    db.prepare(query)
+        .then(|res| tack_on(res, db))
+        .and_then(move |(q, mut db)| {
+            db.query(&q, &param_refs(&params))
+                .collect()
+                .then(|res| tack_on(res, db))
+        })
+}
+
+/// Prepares and runs `query`, resolving to the first row returned, if any.
+pub fn query_opt(
+    mut db: Client,
+    query: &str,
+    params: Params,
+) -> impl Future<Item = (Option<Row>, Client), Error = (tokio_postgres::Error, Client)> {
+    db.prepare(query)
+        .then(|res| tack_on(res, db))
+        .and_then(move |(q, mut db)| {
+            db.query(&q, &param_refs(&params))
+                .into_future()
+                .map(|(row, _)| row)
+                .map_err(|(err, _)| err)
+                .then(|res| tack_on(res, db))
+        })
+}
+
+/// Runs `f` inside a database transaction, which is rolled back if `f` fails.
+pub fn transaction<F, U, T>(
+    mut db: Client,
+    f: F,
+) -> impl Future<Item = (T, Client), Error = (crate::Error, Client)>
+where
+    F: FnOnce(Client) -> U,
+    U: IntoFuture<Item = (T, Client), Error = (crate::Error, Client)>,
+{
+    db.simple_query("BEGIN")
+        .collect()
+        .then(|res| tack_on(res, db))
+        .map_err(|(err, db)| (err.into(), db))
+        .and_then(|(_, db)| f(db))
+        .and_then(|(value, mut db)| {
+            db.simple_query("COMMIT")
+                .collect()
+                .then(|res| tack_on(res, db))
+                .map(|(_, db)| (value, db))
+                .map_err(|(err, db)| (err.into(), db))
+        })
+        .or_else(|(err, mut db)| {
+            db.simple_query("ROLLBACK")
+                .collect()
+                .then(move |_| Err((err, db)))
+        })
+}
+
+/// Converts the error of a database operation so that it can be chained inside `transaction`.
+pub fn lift<T, U>(future: U) -> impl Future<Item = (T, Client), Error = (crate::Error, Client)>
+where
+    U: Future<Item = (T, Client), Error = (tokio_postgres::Error, Client)>,
+{
+    future.map_err(|(err, db)| (err.into(), db))
+}
@@ -0,0 +1,173 @@
+use futures::Future;
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use crate::authentication::AuthContext;
+use crate::db;
+use crate::user_interactive_auth::{self, Flow};
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+const DEVICES_QUERY: &str = "SELECT id, display_name, last_seen_ip, \
+                             (extract(epoch FROM last_seen_ts) * 1000)::bigint \
+                             FROM devices WHERE user_id=$1 ORDER BY id";
+
+const DEVICE_QUERY: &str = "SELECT id, display_name, last_seen_ip, \
+                            (extract(epoch FROM last_seen_ts) * 1000)::bigint \
+                            FROM devices WHERE user_id=$1 AND id=$2";
+
+// A missing display name leaves the current one alone.
+const UPDATE_DEVICE_QUERY: &str =
+    "UPDATE devices SET display_name=COALESCE($3, display_name) WHERE user_id=$1 AND id=$2";
+
+// Deleting a device also deletes its access tokens.
+const DELETE_DEVICE_QUERY: &str = "DELETE FROM devices WHERE user_id=$1 AND id=$2";
+
+// Runs as a single statement, so either every device is deleted or none are.
+const DELETE_DEVICES_QUERY: &str = "DELETE FROM devices WHERE user_id=$1 AND id = ANY($2)";
+
+const DELETE_DEVICE_FLOWS: &[Flow] = &[&[user_interactive_auth::PASSWORD]];
+
+const DEVICE_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "Unknown device");
+
+fn device_json(row: &tokio_postgres::Row) -> serde_json::Value {
+    json!({
+        "device_id": row.get::<_, String>(0),
+        "display_name": row.get::<_, Option<String>>(1),
+        "last_seen_ip": row.get::<_, Option<String>>(2),
+        "last_seen_ts": row.get::<_, Option<i64>>(3),
+    })
+}
+
+/// Lists every device belonging to the calling user.
+pub fn get_devices(server: &LMServer, auth: AuthContext, _req: Request<Body>) -> EndpointFutureBox {
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::query(db, DEVICES_QUERY, params![auth.user_id]))
+            .map_err(crate::Error::from)
+            .map(|rows| {
+                let devices: Vec<_> = rows.iter().map(device_json).collect();
+                json_response(json!({ "devices": devices }))
+            }),
+    )
+}
+
+pub fn get_device(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    device_id: String,
+) -> EndpointFutureBox {
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::query_opt(db, DEVICE_QUERY, params![auth.user_id, device_id]))
+            .map_err(crate::Error::from)
+            .and_then(|row| Ok(json_response(device_json(&row.ok_or(DEVICE_NOT_FOUND)?)))),
+    )
+}
+
+#[derive(Deserialize)]
+struct UpdateDeviceReqBody {
+    display_name: Option<String>,
+}
+
+/// Sets the display name of one of the calling user's devices.
+pub fn update_device(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    device_id: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |body: UpdateDeviceReqBody| {
+                db_pool
+                    .run(move |db| {
+                        db::execute(
+                            db,
+                            UPDATE_DEVICE_QUERY,
+                            params![auth.user_id, device_id, body.display_name],
+                        )
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .and_then(|updated| {
+                if updated == 0 {
+                    Err(DEVICE_NOT_FOUND.into())
+                } else {
+                    Ok(json_response(json!({})))
+                }
+            }),
+    )
+}
+
+#[derive(Deserialize)]
+struct DeleteDeviceReqBody {
+    auth: Option<serde_json::Value>,
+}
+
+/// Deletes one of the calling user's devices, revoking its access tokens.
+pub fn delete_device(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    device_id: String,
+) -> EndpointFutureBox {
+    let server = server.clone();
+
+    Box::new(
+        parse_json_body(req.into_body()).and_then(move |body: DeleteDeviceReqBody| {
+            let db_pool = server.db_pool.clone();
+            let user_id = auth.user_id;
+
+            user_interactive_auth::check(&server, DELETE_DEVICE_FLOWS, Some(user_id), body.auth)
+                .and_then(move |()| {
+                    db_pool
+                        .run(move |db| {
+                            db::execute(db, DELETE_DEVICE_QUERY, params![user_id, device_id])
+                        })
+                        .map_err(crate::Error::from)
+                })
+                .map(|_| json_response(json!({})))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct DeleteDevicesReqBody {
+    devices: Vec<String>,
+    auth: Option<serde_json::Value>,
+}
+
+/// Deletes several of the calling user's devices at once, behind a single user-interactive
+/// authentication check.
+pub fn delete_devices(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+) -> EndpointFutureBox {
+    let server = server.clone();
+
+    Box::new(
+        parse_json_body(req.into_body()).and_then(move |body: DeleteDevicesReqBody| {
+            let db_pool = server.db_pool.clone();
+            let user_id = auth.user_id;
+            let devices = body.devices;
+
+            user_interactive_auth::check(&server, DELETE_DEVICE_FLOWS, Some(user_id), body.auth)
+                .and_then(move |()| {
+                    db_pool
+                        .run(move |db| {
+                            db::execute(db, DELETE_DEVICES_QUERY, params![user_id, devices])
+                        })
+                        .map_err(crate::Error::from)
+                })
+                .map(|_| json_response(json!({})))
+        }),
+    )
+}
@@ -0,0 +1,1097 @@
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use serde_json::Value;
+use std::convert::TryFrom;
+
+use crate::events::{self, Event, StateMap};
+use crate::power_levels::{self, PowerLevels};
+use crate::room_versions::{EventIdFormat, RoomVersion};
+use crate::user_id::UserId;
+
+/// Why an event was rejected by the authorization rules.
+pub type AuthError = &'static str;
+
+/// The server name of a user, room or event ID.
+fn server_name(id: &str) -> Option<&str> {
+    id.split_once(':').map(|(_, server_name)| server_name)
+}
+
+fn membership<'a>(state: &'a StateMap, user_id: &str) -> &'a str {
+    events::get_state(state, "m.room.member", user_id)
+        .and_then(Event::membership)
+        .unwrap_or("leave")
+}
+
+fn join_rule(state: &StateMap) -> &str {
+    events::get_state(state, "m.room.join_rules", "")
+        .and_then(|event| event.content["join_rule"].as_str())
+        .unwrap_or("invite")
+}
+
+/// The power levels in effect in `state`.
+pub fn power_levels(version: &RoomVersion, state: &StateMap) -> PowerLevels {
+    match events::get_state(state, "m.room.power_levels", "") {
+        Some(event) => PowerLevels::from_content(&event.content),
+        None => PowerLevels::without_event(
+            events::get_state(state, "m.room.create", "").and_then(|event| version.creator(event)),
+        ),
+    }
+}
+
+/// Checks whether `event` is allowed by the authorization rules, given the state of the room
+/// before it, which must contain at least the events selected by `events::auth_types`.
+pub fn check(version: &RoomVersion, event: &Event, auth_state: &StateMap) -> Result<(), AuthError> {
+    if event.type_ == "m.room.create" {
+        return check_create(version, event);
+    }
+
+    let create =
+        events::get_state(auth_state, "m.room.create", "").ok_or("The room has no create event")?;
+    if create.content["m.federate"] == Value::Bool(false)
+        && server_name(&event.sender) != server_name(&create.sender)
+    {
+        return Err("The room is not federated");
+    }
+
+    if event.type_ == "m.room.aliases" && version.special_case_aliases {
+        let state_key = event
+            .state_key
+            .as_ref()
+            .ok_or("Aliases events must have a state key")?;
+        return if server_name(&event.sender) == Some(state_key) {
+            Ok(())
+        } else {
+            Err("Aliases may only be set by their own server")
+        };
+    }
+
+    if event.type_ == "m.room.member" {
+        return check_membership(version, event, create, auth_state);
+    }
+
+    if membership(auth_state, &event.sender) != "join" {
+        return Err("The sender is not joined to the room");
+    }
+
+    let power_levels = power_levels(version, auth_state);
+    let sender_level = power_levels.user_level(&event.sender);
+
+    if event.type_ == "m.room.third_party_invite" {
+        return if sender_level >= power_levels.invite {
+            Ok(())
+        } else {
+            Err("Insufficient power level to invite")
+        };
+    }
+
+    if sender_level < power_levels.event_level(&event.type_, event.state_key.is_some()) {
+        return Err("Insufficient power level to send this event");
+    }
+
+    if let Some(ref state_key) = event.state_key {
+        if state_key.starts_with('@') && *state_key != event.sender {
+            return Err("State keyed by a user ID may only be sent by that user");
+        }
+    }
+
+    match event.type_.as_str() {
+        "m.room.power_levels" => check_power_levels(version, event, auth_state, sender_level),
+        "m.room.redaction" => check_redaction(version, event, &power_levels, sender_level),
+        _ => Ok(()),
+    }
+}
+
+fn check_create(version: &RoomVersion, event: &Event) -> Result<(), AuthError> {
+    if !event.prev_events.is_empty() {
+        return Err("Create events must be the first event in the room");
+    }
+    if server_name(&event.room_id) != server_name(&event.sender) {
+        return Err("The room ID must belong to the creator's server");
+    }
+    if !version.implicit_room_creator && !event.content["creator"].is_string() {
+        return Err("Create events must have a creator");
+    }
+
+    Ok(())
+}
+
+fn check_membership(
+    version: &RoomVersion,
+    event: &Event,
+    create: &Event,
+    state: &StateMap,
+) -> Result<(), AuthError> {
+    let target = event
+        .state_key
+        .as_ref()
+        .ok_or("Membership events must have a state key")?;
+    let new_membership = event.content["membership"]
+        .as_str()
+        .ok_or("Membership events must have a membership")?;
+
+    let sender_membership = membership(state, &event.sender);
+    let target_membership = membership(state, target);
+    let power_levels = power_levels(version, state);
+    let sender_level = power_levels.user_level(&event.sender);
+    let target_level = power_levels.user_level(target);
+    let invited_or_joined = sender_membership == "join" || sender_membership == "invite";
+
+    match new_membership {
+        "join" => {
+            // The creator joins straight after creating the room, before there are join rules.
+            if event.prev_events == [create.event_id.as_str()]
+                && version.creator(create) == Some(target)
+            {
+                return Ok(());
+            }
+            if event.sender != *target {
+                return Err("Users cannot be joined by someone else");
+            }
+            if sender_membership == "ban" {
+                return Err("The sender is banned from the room");
+            }
+
+            match join_rule(state) {
+                "public" => Ok(()),
+                "invite" if invited_or_joined => Ok(()),
+                "invite" => Err("The room is invite only"),
+                "knock" if version.knocking && invited_or_joined => Ok(()),
+                "knock" if version.knocking => Err("The room is invite only"),
+                "restricted" if version.restricted_joins => {
+                    check_restricted_join(event, state, &power_levels, invited_or_joined)
+                }
+                "knock_restricted" if version.knock_restricted_joins => {
+                    check_restricted_join(event, state, &power_levels, invited_or_joined)
+                }
+                _ => Err("The join rules do not allow joining"),
+            }
+        }
+        "invite" => {
+            if !event.content["third_party_invite"].is_null() {
+                return check_third_party_invite(event, target, target_membership, state);
+            }
+            if sender_membership != "join" {
+                return Err("The sender is not joined to the room");
+            }
+            if target_membership == "join" || target_membership == "ban" {
+                return Err("The user is already joined or banned");
+            }
+
+            if sender_level >= power_levels.invite {
+                Ok(())
+            } else {
+                Err("Insufficient power level to invite")
+            }
+        }
+        "leave" => {
+            if event.sender == *target {
+                return if invited_or_joined || (version.knocking && sender_membership == "knock") {
+                    Ok(())
+                } else {
+                    Err("The sender is not in the room")
+                };
+            }
+            if sender_membership != "join" {
+                return Err("The sender is not joined to the room");
+            }
+            if target_membership == "ban" && sender_level < power_levels.ban {
+                return Err("Insufficient power level to unban");
+            }
+
+            if sender_level >= power_levels.kick && target_level < sender_level {
+                Ok(())
+            } else {
+                Err("Insufficient power level to kick")
+            }
+        }
+        "ban" => {
+            if sender_membership != "join" {
+                return Err("The sender is not joined to the room");
+            }
+
+            if sender_level >= power_levels.ban && target_level < sender_level {
+                Ok(())
+            } else {
+                Err("Insufficient power level to ban")
+            }
+        }
+        "knock" if version.knocking => {
+            let knockable = match join_rule(state) {
+                "knock" => true,
+                "knock_restricted" => version.knock_restricted_joins,
+                _ => false,
+            };
+            if !knockable {
+                return Err("The join rules do not allow knocking");
+            }
+            if event.sender != *target {
+                return Err("Users cannot knock on behalf of someone else");
+            }
+
+            match sender_membership {
+                "ban" | "invite" | "join" => Err("The user is already banned, invited or joined"),
+                _ => Ok(()),
+            }
+        }
+        _ => Err("Unknown membership"),
+    }
+}
+
+/// Joins to restricted rooms by users who were not invited must name a member of the room who
+/// can invite them, whose server checked that they are allowed to join.
+fn check_restricted_join(
+    event: &Event,
+    state: &StateMap,
+    power_levels: &PowerLevels,
+    invited_or_joined: bool,
+) -> Result<(), AuthError> {
+    if invited_or_joined {
+        return Ok(());
+    }
+
+    let authoriser = event.content["join_authorised_via_users_server"]
+        .as_str()
+        .ok_or("The room is restricted")?;
+    if membership(state, authoriser) == "join"
+        && power_levels.user_level(authoriser) >= power_levels.invite
+    {
+        Ok(())
+    } else {
+        Err("The join was not authorised by a user who can invite")
+    }
+}
+
+fn check_third_party_invite(
+    event: &Event,
+    target: &str,
+    target_membership: &str,
+    state: &StateMap,
+) -> Result<(), AuthError> {
+    if target_membership == "ban" {
+        return Err("The user is banned from the room");
+    }
+
+    let signed = &event.content["third_party_invite"]["signed"];
+    let (mxid, token) = match (signed["mxid"].as_str(), signed["token"].as_str()) {
+        (Some(mxid), Some(token)) => (mxid, token),
+        _ => return Err("Third-party invites must be signed"),
+    };
+    if mxid != target {
+        return Err("The third-party invite is for another user");
+    }
+
+    let invite = events::get_state(state, "m.room.third_party_invite", token)
+        .ok_or("No matching third-party invite")?;
+    if invite.sender != event.sender {
+        return Err("The third-party invite was sent by another user");
+    }
+
+    if verify_signed(signed, &invite.content) {
+        Ok(())
+    } else {
+        Err("The third-party invite is not signed by the identity server")
+    }
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=');
+    base64::decode_config(encoded, base64::STANDARD_NO_PAD)
+        .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+        .ok()
+}
+
+fn verify_ed25519(public_key: &str, signature: &str, message: &[u8]) -> bool {
+    let public_key = decode_base64(public_key).and_then(|key| PublicKey::from_bytes(&key).ok());
+    let signature =
+        decode_base64(signature).and_then(|signature| Signature::try_from(&signature[..]).ok());
+
+    match (public_key, signature) {
+        (Some(public_key), Some(signature)) => public_key.verify(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// Checks that the `signed` block of a third-party invite is signed by one of the public keys of
+/// the `m.room.third_party_invite` event it refers to.
+fn verify_signed(signed: &Value, invite_content: &Value) -> bool {
+    let mut message = signed.clone();
+    if let Some(message) = message.as_object_mut() {
+        message.remove("signatures");
+        message.remove("unsigned");
+    }
+    let message = events::canonical_json(&message);
+
+    let mut public_keys: Vec<&str> = invite_content["public_keys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|key| key["public_key"].as_str())
+        .collect();
+    public_keys.extend(invite_content["public_key"].as_str());
+
+    let signatures: Vec<&str> = signed["signatures"]
+        .as_object()
+        .into_iter()
+        .flat_map(|servers| servers.values())
+        .filter_map(Value::as_object)
+        .flat_map(|keys| keys.values())
+        .filter_map(Value::as_str)
+        .collect();
+
+    public_keys.iter().any(|public_key| {
+        signatures
+            .iter()
+            .any(|signature| verify_ed25519(public_key, signature, message.as_bytes()))
+    })
+}
+
+fn check_power_levels(
+    version: &RoomVersion,
+    event: &Event,
+    state: &StateMap,
+    sender_level: i64,
+) -> Result<(), AuthError> {
+    let valid_level = |level: &Value| {
+        if version.integer_power_levels {
+            level.is_i64()
+        } else {
+            power_levels::parse_level(level).is_some()
+        }
+    };
+
+    if let Some(users) = event.content.get("users") {
+        let users = users.as_object().ok_or("Invalid users in power levels")?;
+        if users
+            .iter()
+            .any(|(user_id, level)| UserId::parse(user_id).is_err() || !valid_level(level))
+        {
+            return Err("Invalid users in power levels");
+        }
+    }
+
+    if version.integer_power_levels {
+        let levels = [
+            "users_default",
+            "events_default",
+            "state_default",
+            "ban",
+            "redact",
+            "kick",
+            "invite",
+        ];
+        if levels
+            .iter()
+            .filter_map(|key| event.content.get(key))
+            .any(|level| !level.is_i64())
+        {
+            return Err("Power levels must be integers");
+        }
+        if ["events", "notifications"]
+            .iter()
+            .filter_map(|key| event.content.get(key))
+            .any(|levels| {
+                !levels
+                    .as_object()
+                    .is_some_and(|levels| levels.values().all(Value::is_i64))
+            })
+        {
+            return Err("Power levels must be integers");
+        }
+    }
+
+    let old = match events::get_state(state, "m.room.power_levels", "") {
+        Some(old) => PowerLevels::from_content(&old.content),
+        None => return Ok(()),
+    };
+    let new = PowerLevels::from_content(&event.content);
+
+    let changed_above_sender = |old: Option<i64>, new: Option<i64>| {
+        old != new
+            && (old.unwrap_or(i64::MIN) > sender_level || new.unwrap_or(i64::MIN) > sender_level)
+    };
+
+    let levels = [
+        (old.users_default, new.users_default),
+        (old.events_default, new.events_default),
+        (old.state_default, new.state_default),
+        (old.ban, new.ban),
+        (old.redact, new.redact),
+        (old.kick, new.kick),
+        (old.invite, new.invite),
+    ];
+    if levels
+        .iter()
+        .any(|&(old, new)| changed_above_sender(Some(old), Some(new)))
+    {
+        return Err("Cannot change a power level above your own");
+    }
+
+    if old
+        .events
+        .keys()
+        .chain(new.events.keys())
+        .any(|event_type| {
+            changed_above_sender(
+                old.events.get(event_type).cloned(),
+                new.events.get(event_type).cloned(),
+            )
+        })
+    {
+        return Err("Cannot change an event power level above your own");
+    }
+
+    for user_id in old.users.keys().chain(new.users.keys()) {
+        let (old_level, new_level) = (old.users.get(user_id), new.users.get(user_id));
+        if old_level == new_level {
+            continue;
+        }
+        if *user_id != event.sender && old_level.is_some_and(|&level| level >= sender_level) {
+            return Err("Cannot change the power level of a user at or above your own");
+        }
+        if new_level.is_some_and(|&level| level > sender_level) {
+            return Err("Cannot raise a user above your own power level");
+        }
+    }
+
+    Ok(())
+}
+
+/// Where event IDs name the server which created them, only redactions of that server's own
+/// events are allowed without the `redact` level. Otherwise the redacted event cannot be told
+/// from its ID, so the redaction is allowed here and checked by `may_apply_redaction` instead.
+fn check_redaction(
+    version: &RoomVersion,
+    event: &Event,
+    power_levels: &PowerLevels,
+    sender_level: i64,
+) -> Result<(), AuthError> {
+    if sender_level >= power_levels.redact || version.event_id_format != EventIdFormat::Arbitrary {
+        return Ok(());
+    }
+
+    let redacts = version.redacts(event);
+    if redacts.is_some() && redacts.and_then(server_name) == server_name(&event.sender) {
+        Ok(())
+    } else {
+        Err("Insufficient power level to redact")
+    }
+}
+
+/// Checks whether an authorized redaction may take effect on an event sent by `original_sender`,
+/// which is only the case for moderators and the original sender.
+pub fn may_apply_redaction(
+    version: &RoomVersion,
+    redaction: &Event,
+    original_sender: Option<&str>,
+    auth_state: &StateMap,
+) -> bool {
+    let power_levels = power_levels(version, auth_state);
+    power_levels.user_level(&redaction.sender) >= power_levels.redact
+        || original_sender == Some(redaction.sender.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AuthError;
+    use crate::events::{Event, StateMap};
+    use crate::room_versions::{self, RoomVersion};
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use serde_json::{json, Value};
+
+    const ROOM_ID: &str = "!room:example.com";
+    const ALICE: &str = "@alice:example.com";
+    const BOB: &str = "@bob:example.com";
+    const CAROL: &str = "@carol:example.com";
+    const DAVE: &str = "@dave:example.com";
+
+    fn version(id: &str) -> &'static RoomVersion {
+        room_versions::get(id).unwrap()
+    }
+
+    /// Checks `event` in a version 2 room, the version of every room created before versions
+    /// could be chosen.
+    fn check(event: &Event, auth_state: &StateMap) -> Result<(), AuthError> {
+        super::check(version("2"), event, auth_state)
+    }
+
+    fn event(type_: &str, state_key: Option<&str>, sender: &str, content: Value) -> Event {
+        Event {
+            event_id: format!("${}:example.com", type_),
+            room_id: ROOM_ID.to_owned(),
+            type_: type_.to_owned(),
+            state_key: state_key.map(str::to_owned),
+            sender: sender.to_owned(),
+            origin_server_ts: 0,
+            content,
+            redacts: None,
+            depth: 10,
+            prev_events: vec!["$previous:example.com".to_owned()],
+            auth_events: Vec::new(),
+        }
+    }
+
+    fn member(user_id: &str, membership: &str) -> Event {
+        event(
+            "m.room.member",
+            Some(user_id),
+            user_id,
+            json!({ "membership": membership }),
+        )
+    }
+
+    fn create() -> Event {
+        let mut create = event(
+            "m.room.create",
+            Some(""),
+            ALICE,
+            json!({ "creator": ALICE }),
+        );
+        create.prev_events = Vec::new();
+        create
+    }
+
+    fn state(events: Vec<Event>) -> StateMap {
+        crate::events::state_map(events)
+    }
+
+    /// A room created by Alice, who has power level 100, where Bob is joined with power level 50.
+    fn room(join_rule: &str) -> StateMap {
+        state(vec![
+            create(),
+            member(ALICE, "join"),
+            member(BOB, "join"),
+            event(
+                "m.room.power_levels",
+                Some(""),
+                ALICE,
+                json!({ "users": { ALICE: 100, BOB: 50 } }),
+            ),
+            event(
+                "m.room.join_rules",
+                Some(""),
+                ALICE,
+                json!({ "join_rule": join_rule }),
+            ),
+        ])
+    }
+
+    fn with(mut state: StateMap, events: Vec<Event>) -> StateMap {
+        state.extend(crate::events::state_map(events));
+        state
+    }
+
+    fn membership_change(sender: &str, target: &str, membership: &str) -> Event {
+        event(
+            "m.room.member",
+            Some(target),
+            sender,
+            json!({ "membership": membership }),
+        )
+    }
+
+    #[test]
+    fn create_event() {
+        assert!(check(&create(), &StateMap::new()).is_ok());
+
+        let mut with_prev = create();
+        with_prev.prev_events = vec!["$x:example.com".to_owned()];
+        assert!(check(&with_prev, &StateMap::new()).is_err());
+
+        let mut foreign = create();
+        foreign.sender = "@alice:example.org".to_owned();
+        assert!(check(&foreign, &StateMap::new()).is_err());
+
+        let mut no_creator = create();
+        no_creator.content = json!({});
+        assert!(check(&no_creator, &StateMap::new()).is_err());
+    }
+
+    #[test]
+    fn requires_create_event() {
+        let message = event("m.room.message", None, ALICE, json!({}));
+        assert!(check(&message, &StateMap::new()).is_err());
+    }
+
+    #[test]
+    fn unfederated_room() {
+        let mut create = create();
+        create.content["m.federate"] = json!(false);
+        let state = with(room("public"), vec![create]);
+
+        let join = member("@dave:example.org", "join");
+        assert!(check(&join, &state).is_err());
+        assert!(check(&member(CAROL, "join"), &state).is_ok());
+    }
+
+    #[test]
+    fn creator_join() {
+        let mut join = member(ALICE, "join");
+        join.prev_events = vec![create().event_id];
+        assert!(check(&join, &state(vec![create()])).is_ok());
+
+        let mut join = member(BOB, "join");
+        join.prev_events = vec![create().event_id];
+        assert!(check(&join, &state(vec![create()])).is_err());
+    }
+
+    #[test]
+    fn join_rules() {
+        assert!(check(&member(CAROL, "join"), &room("public")).is_ok());
+        assert!(check(&member(CAROL, "join"), &room("invite")).is_err());
+        assert!(check(&member(CAROL, "join"), &room("private")).is_err());
+
+        let invited = with(
+            room("invite"),
+            vec![membership_change(ALICE, CAROL, "invite")],
+        );
+        assert!(check(&member(CAROL, "join"), &invited).is_ok());
+
+        let banned = with(room("public"), vec![membership_change(ALICE, CAROL, "ban")]);
+        assert!(check(&member(CAROL, "join"), &banned).is_err());
+
+        assert!(check(&membership_change(ALICE, CAROL, "join"), &room("public")).is_err());
+    }
+
+    #[test]
+    fn invites() {
+        let room = room("invite");
+        assert!(check(&membership_change(BOB, CAROL, "invite"), &room).is_ok());
+        assert!(check(
+            &membership_change(CAROL, "@dave:example.com", "invite"),
+            &room
+        )
+        .is_err());
+        assert!(check(&membership_change(ALICE, BOB, "invite"), &room).is_err());
+
+        let restricted = with(
+            room.clone(),
+            vec![event(
+                "m.room.power_levels",
+                Some(""),
+                ALICE,
+                json!({ "users": { ALICE: 100, BOB: 50 }, "invite": 75 }),
+            )],
+        );
+        assert!(check(&membership_change(BOB, CAROL, "invite"), &restricted).is_err());
+        assert!(check(&membership_change(ALICE, CAROL, "invite"), &restricted).is_ok());
+
+        let banned = with(room, vec![membership_change(ALICE, CAROL, "ban")]);
+        assert!(check(&membership_change(ALICE, CAROL, "invite"), &banned).is_err());
+    }
+
+    #[test]
+    fn leaving_and_kicking() {
+        let room = room("invite");
+        assert!(check(&member(BOB, "leave"), &room).is_ok());
+        assert!(check(&member(CAROL, "leave"), &room).is_err());
+
+        let invited = with(
+            room.clone(),
+            vec![membership_change(ALICE, CAROL, "invite")],
+        );
+        assert!(check(&member(CAROL, "leave"), &invited).is_ok());
+
+        assert!(check(&membership_change(ALICE, BOB, "leave"), &room).is_ok());
+        assert!(check(&membership_change(BOB, ALICE, "leave"), &room).is_err());
+
+        let joined = with(room, vec![member(CAROL, "join")]);
+        assert!(check(&membership_change(BOB, CAROL, "leave"), &joined).is_ok());
+        assert!(check(&membership_change(CAROL, BOB, "leave"), &joined).is_err());
+    }
+
+    #[test]
+    fn banning_and_unbanning() {
+        let room = with(room("public"), vec![member(CAROL, "join")]);
+        assert!(check(&membership_change(BOB, CAROL, "ban"), &room).is_ok());
+        assert!(check(&membership_change(BOB, ALICE, "ban"), &room).is_err());
+        assert!(check(&membership_change(CAROL, BOB, "ban"), &room).is_err());
+
+        let banned = with(room, vec![membership_change(ALICE, CAROL, "ban")]);
+        assert!(check(&membership_change(BOB, CAROL, "leave"), &banned).is_ok());
+        assert!(check(&member(CAROL, "leave"), &banned).is_err());
+
+        let strict = with(
+            banned,
+            vec![event(
+                "m.room.power_levels",
+                Some(""),
+                ALICE,
+                json!({ "users": { ALICE: 100, BOB: 50 }, "ban": 60, "kick": 10 }),
+            )],
+        );
+        assert!(check(&membership_change(BOB, CAROL, "leave"), &strict).is_err());
+    }
+
+    #[test]
+    fn unknown_membership() {
+        assert!(check(&member(BOB, "knock"), &room("public")).is_err());
+        let mut no_membership = member(BOB, "join");
+        no_membership.content = json!({});
+        assert!(check(&no_membership, &room("public")).is_err());
+    }
+
+    #[test]
+    fn third_party_invites() {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let keypair = Keypair {
+            public: (&secret).into(),
+            secret,
+        };
+        let public_key = base64::encode_config(keypair.public.as_bytes(), base64::STANDARD_NO_PAD);
+
+        let invite = event(
+            "m.room.third_party_invite",
+            Some("token"),
+            BOB,
+            json!({ "public_key": public_key, "display_name": "c...@example.com" }),
+        );
+        assert!(check(&invite, &room("invite")).is_ok());
+        let room = with(room("invite"), vec![invite]);
+
+        let signed = json!({ "mxid": CAROL, "token": "token" });
+        let signature = keypair.sign(crate::events::canonical_json(&signed).as_bytes());
+        let mut signed_with_signature = signed.clone();
+        signed_with_signature["signatures"] = json!({
+            "id.example.com": {
+                "ed25519:0": base64::encode_config(&signature.to_bytes()[..], base64::STANDARD_NO_PAD),
+            },
+        });
+        let third_party_member = |sender: &str, signed: &Value| {
+            event(
+                "m.room.member",
+                Some(CAROL),
+                sender,
+                json!({
+                    "membership": "invite",
+                    "third_party_invite": { "display_name": "c...", "signed": signed },
+                }),
+            )
+        };
+
+        assert!(check(&third_party_member(BOB, &signed_with_signature), &room).is_ok());
+        assert!(check(&third_party_member(ALICE, &signed_with_signature), &room).is_err());
+        assert!(check(&third_party_member(BOB, &signed), &room).is_err());
+
+        let mut forged = signed_with_signature.clone();
+        forged["mxid"] = json!("@dave:example.com");
+        assert!(check(&third_party_member(BOB, &forged), &room).is_err());
+
+        let mut unknown_token = signed_with_signature;
+        unknown_token["token"] = json!("other");
+        assert!(check(&third_party_member(BOB, &unknown_token), &room).is_err());
+    }
+
+    #[test]
+    fn third_party_invite_event_power() {
+        let room = with(room("invite"), vec![member(CAROL, "join")]);
+        let strict = with(
+            room.clone(),
+            vec![event(
+                "m.room.power_levels",
+                Some(""),
+                ALICE,
+                json!({ "users": { ALICE: 100, BOB: 50 }, "invite": 50 }),
+            )],
+        );
+        let invite = |sender| event("m.room.third_party_invite", Some("t"), sender, json!({}));
+        assert!(check(&invite(CAROL), &room).is_ok());
+        assert!(check(&invite(CAROL), &strict).is_err());
+        assert!(check(&invite(BOB), &strict).is_ok());
+    }
+
+    #[test]
+    fn sender_must_be_joined() {
+        let message = event("m.room.message", None, CAROL, json!({}));
+        assert!(check(&message, &room("public")).is_err());
+
+        let message = event("m.room.message", None, BOB, json!({}));
+        assert!(check(&message, &room("public")).is_ok());
+    }
+
+    #[test]
+    fn event_power_levels() {
+        let room = with(room("public"), vec![member(CAROL, "join")]);
+        let topic = |sender| event("m.room.topic", Some(""), sender, json!({ "topic": "x" }));
+        assert!(check(&topic(BOB), &room).is_ok());
+        assert!(check(&topic(CAROL), &room).is_err());
+
+        let message = event("m.room.message", None, CAROL, json!({}));
+        assert!(check(&message, &room).is_ok());
+    }
+
+    #[test]
+    fn power_levels_without_event() {
+        let state = state(vec![create(), member(ALICE, "join"), member(BOB, "join")]);
+        let topic = |sender| event("m.room.topic", Some(""), sender, json!({}));
+        assert!(check(&topic(ALICE), &state).is_ok());
+        assert!(check(&topic(BOB), &state).is_ok());
+
+        let ban = membership_change(BOB, ALICE, "ban");
+        assert!(check(&ban, &state).is_err());
+        assert!(check(&membership_change(ALICE, BOB, "ban"), &state).is_ok());
+    }
+
+    #[test]
+    fn user_state_keys() {
+        let room = room("public");
+        let own = event("m.custom", Some(BOB), BOB, json!({}));
+        assert!(check(&own, &room).is_ok());
+        let other = event("m.custom", Some(ALICE), BOB, json!({}));
+        assert!(check(&other, &room).is_err());
+    }
+
+    #[test]
+    fn aliases() {
+        let room = room("public");
+        let aliases = event("m.room.aliases", Some("example.com"), CAROL, json!({}));
+        assert!(check(&aliases, &room).is_ok());
+        let aliases = event("m.room.aliases", Some("example.org"), ALICE, json!({}));
+        assert!(check(&aliases, &room).is_err());
+    }
+
+    fn power_levels_change(sender: &str, content: Value) -> Event {
+        event("m.room.power_levels", Some(""), sender, content)
+    }
+
+    #[test]
+    fn changing_power_levels() {
+        let room = with(room("public"), vec![member(CAROL, "join")]);
+        let room = with(
+            room,
+            vec![power_levels_change(
+                ALICE,
+                json!({
+                    "users": { ALICE: 100, BOB: 50, CAROL: 50 },
+                    "events": { "m.room.power_levels": 50, "m.room.tombstone": 100 },
+                }),
+            )],
+        );
+
+        // Bob may promote a user up to his own level, but not above it.
+        let promote = |level| {
+            power_levels_change(
+                BOB,
+                json!({
+                    "users": { ALICE: 100, BOB: 50, CAROL: 50, "@dave:example.com": level },
+                    "events": { "m.room.power_levels": 50, "m.room.tombstone": 100 },
+                }),
+            )
+        };
+        assert!(check(&promote(50), &room).is_ok());
+        assert!(check(&promote(51), &room).is_err());
+
+        // Nor may he demote a user at his own level, although he may demote himself.
+        let demote = |user_id| {
+            let mut users = json!({ ALICE: 100, BOB: 50, CAROL: 50 });
+            users[user_id] = json!(0);
+            power_levels_change(
+                BOB,
+                json!({
+                    "users": users,
+                    "events": { "m.room.power_levels": 50, "m.room.tombstone": 100 },
+                }),
+            )
+        };
+        assert!(check(&demote(CAROL), &room).is_err());
+        assert!(check(&demote(BOB), &room).is_ok());
+
+        // Event levels above his own are out of reach too.
+        let events = |events| {
+            power_levels_change(
+                BOB,
+                json!({ "users": { ALICE: 100, BOB: 50, CAROL: 50 }, "events": events }),
+            )
+        };
+        assert!(check(&events(json!({ "m.room.power_levels": 50 })), &room).is_err());
+        assert!(check(
+            &events(
+                json!({ "m.room.power_levels": 50, "m.room.tombstone": 100, "m.room.name": 40 })
+            ),
+            &room
+        )
+        .is_ok());
+
+        let defaults = |ban| {
+            power_levels_change(
+                BOB,
+                json!({
+                    "users": { ALICE: 100, BOB: 50, CAROL: 50 },
+                    "events": { "m.room.power_levels": 50, "m.room.tombstone": 100 },
+                    "ban": ban,
+                }),
+            )
+        };
+        assert!(check(&defaults(20), &room).is_ok());
+        assert!(check(&defaults(80), &room).is_err());
+    }
+
+    #[test]
+    fn invalid_power_levels() {
+        let room = room("public");
+        let invalid_user = power_levels_change(ALICE, json!({ "users": { "alice": 100 } }));
+        assert!(check(&invalid_user, &room).is_err());
+        let invalid_level = power_levels_change(ALICE, json!({ "users": { ALICE: "high" } }));
+        assert!(check(&invalid_level, &room).is_err());
+    }
+
+    #[test]
+    fn redactions() {
+        let room = with(room("public"), vec![member(CAROL, "join")]);
+        let redaction = |sender, redacts: &str| {
+            let mut redaction = event("m.room.redaction", None, sender, json!({}));
+            redaction.redacts = Some(redacts.to_owned());
+            redaction
+        };
+        assert!(check(&redaction(BOB, "$x:example.org"), &room).is_ok());
+        assert!(check(&redaction(CAROL, "$x:example.com"), &room).is_ok());
+        assert!(check(&redaction(CAROL, "$x:example.org"), &room).is_err());
+
+        // Before version 11, the redacted event is not part of the content.
+        let in_content = event(
+            "m.room.redaction",
+            None,
+            CAROL,
+            json!({ "redacts": "$x:example.com" }),
+        );
+        assert!(check(&in_content, &room).is_err());
+    }
+
+    #[test]
+    fn redactions_with_hashed_event_ids() {
+        let room = with(room("public"), vec![member(CAROL, "join")]);
+        let mut redaction = event("m.room.redaction", None, CAROL, json!({}));
+        redaction.redacts = Some("$abcdef".to_owned());
+        assert_eq!(version("10").redacts(&redaction), Some("$abcdef"));
+        assert!(super::check(version("10"), &redaction, &room).is_ok());
+
+        let v11_redaction = event(
+            "m.room.redaction",
+            None,
+            CAROL,
+            json!({ "redacts": "$abcdef" }),
+        );
+        assert_eq!(version("10").redacts(&v11_redaction), None);
+        assert_eq!(version("11").redacts(&v11_redaction), Some("$abcdef"));
+
+        let apply = |redaction: &Event, original_sender| {
+            super::may_apply_redaction(version("10"), redaction, original_sender, &room)
+        };
+        assert!(apply(&redaction, Some(CAROL)));
+        assert!(!apply(&redaction, Some(ALICE)));
+        assert!(!apply(&redaction, None));
+        redaction.sender = BOB.to_owned();
+        assert!(apply(&redaction, Some(ALICE)));
+    }
+
+    #[test]
+    fn aliases_without_special_case() {
+        let room = room("public");
+        let aliases = event("m.room.aliases", Some("example.com"), CAROL, json!({}));
+        assert!(super::check(version("6"), &aliases, &room).is_err());
+        let aliases = event("m.room.aliases", Some("example.org"), ALICE, json!({}));
+        assert!(super::check(version("6"), &aliases, &room).is_ok());
+    }
+
+    #[test]
+    fn knocking() {
+        let v6 = version("6");
+        let v7 = version("7");
+        let knock = member(CAROL, "knock");
+        assert!(super::check(v6, &knock, &room("knock")).is_err());
+        assert!(super::check(v7, &knock, &room("knock")).is_ok());
+        assert!(super::check(v7, &knock, &room("invite")).is_err());
+        assert!(super::check(v7, &knock, &room("knock_restricted")).is_err());
+        assert!(super::check(version("10"), &knock, &room("knock_restricted")).is_ok());
+        assert!(super::check(
+            v7,
+            &membership_change(ALICE, CAROL, "knock"),
+            &room("knock")
+        )
+        .is_err());
+
+        let invited = with(
+            room("knock"),
+            vec![membership_change(ALICE, CAROL, "invite")],
+        );
+        assert!(super::check(v7, &knock, &invited).is_err());
+        assert!(super::check(v7, &member(CAROL, "join"), &invited).is_ok());
+        assert!(super::check(v7, &member(CAROL, "join"), &room("knock")).is_err());
+
+        let knocked = with(room("knock"), vec![knock]);
+        assert!(super::check(v7, &member(CAROL, "leave"), &knocked).is_ok());
+        assert!(super::check(v6, &member(CAROL, "leave"), &knocked).is_err());
+    }
+
+    #[test]
+    fn restricted_joins() {
+        let v8 = version("8");
+        let room = with(room("restricted"), vec![member(CAROL, "join")]);
+        let join = |authoriser: &str| {
+            event(
+                "m.room.member",
+                Some(DAVE),
+                DAVE,
+                json!({ "membership": "join", "join_authorised_via_users_server": authoriser }),
+            )
+        };
+
+        assert!(super::check(v8, &join(BOB), &room).is_ok());
+        assert!(super::check(version("7"), &join(BOB), &room).is_err());
+        assert!(super::check(v8, &member(DAVE, "join"), &room).is_err());
+        assert!(super::check(v8, &join("@erin:example.com"), &room).is_err());
+
+        let invite_only = with(
+            room.clone(),
+            vec![event(
+                "m.room.power_levels",
+                Some(""),
+                ALICE,
+                json!({ "users": { ALICE: 100, BOB: 50 }, "invite": 50 }),
+            )],
+        );
+        assert!(super::check(v8, &join(CAROL), &room).is_ok());
+        assert!(super::check(v8, &join(CAROL), &invite_only).is_err());
+
+        let invited = with(room, vec![membership_change(BOB, DAVE, "invite")]);
+        assert!(super::check(v8, &member(DAVE, "join"), &invited).is_ok());
+    }
+
+    #[test]
+    fn integer_power_levels() {
+        let room = room("public");
+        let string_level = power_levels_change(ALICE, json!({ "users": { ALICE: "100" } }));
+        assert!(check(&string_level, &room).is_ok());
+        assert!(super::check(version("10"), &string_level, &room).is_err());
+
+        let string_default =
+            power_levels_change(ALICE, json!({ "users": { ALICE: 100 }, "kick": "50" }));
+        assert!(super::check(version("10"), &string_default, &room).is_err());
+        let string_event = power_levels_change(
+            ALICE,
+            json!({ "users": { ALICE: 100 }, "events": { "m.room.name": "50" } }),
+        );
+        assert!(super::check(version("10"), &string_event, &room).is_err());
+        let integers = power_levels_change(
+            ALICE,
+            json!({ "users": { ALICE: 100 }, "kick": 50, "notifications": { "room": 50 } }),
+        );
+        assert!(super::check(version("10"), &integers, &room).is_ok());
+    }
+
+    #[test]
+    fn implicit_room_creator() {
+        let v11 = version("11");
+        let mut create = create();
+        create.content = json!({ "room_version": "11" });
+        assert!(check(&create, &StateMap::new()).is_err());
+        assert!(super::check(v11, &create, &StateMap::new()).is_ok());
+
+        let mut join = member(ALICE, "join");
+        join.prev_events = vec![create.event_id.clone()];
+        assert!(super::check(v11, &join, &state(vec![create.clone()])).is_ok());
+
+        let room = state(vec![create, member(ALICE, "join")]);
+        let ban = membership_change(ALICE, CAROL, "ban");
+        assert!(super::check(v11, &ban, &room).is_ok());
+        assert!(check(&ban, &room).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use futures::{future, Future};
+use serde_json::Value;
+use tokio_postgres::Client;
+
+use crate::db::{self, TransactionFutureBox};
+use crate::event_auth;
+use crate::events::{self, Event, StateMap};
+use crate::room_versions::{self, EventIdFormat, RedactionRules, RoomVersion};
+use crate::{error_code, ErrorBody};
+
+const EVENT_QUERY: &str = "SELECT id, room_id, type, state_key, sender, origin_server_ts, \
+                           content, depth, prev_events, auth_events, redacts \
+                           FROM events WHERE id=$1";
+
+const CURRENT_STATE_QUERY: &str = "SELECT events.id, events.room_id, events.type, \
+     events.state_key, events.sender, events.origin_server_ts, events.content, events.depth, \
+     events.prev_events, events.auth_events, events.redacts \
+     FROM current_state INNER JOIN events ON events.id = current_state.event_id \
+     WHERE current_state.room_id=$1";
+
+const EVENTS_AFTER_QUERY: &str = "SELECT id, room_id, type, state_key, sender, \
+     origin_server_ts, content, depth, prev_events, auth_events, redacts, stream_ordering \
+     FROM events WHERE room_id=$1 AND stream_ordering > $2 \
+     ORDER BY stream_ordering LIMIT $3";
+
+const LOCK_ROOM_QUERY: &str = "SELECT version FROM rooms WHERE id=$1 FOR UPDATE";
+
+// Events are only created locally so far, so the graph of each room is a single chain.
+const LATEST_EVENT_QUERY: &str =
+    "SELECT id, depth FROM events WHERE room_id=$1 ORDER BY stream_ordering DESC LIMIT 1";
+
+const INSERT_EVENT_QUERY: &str = "INSERT INTO events (id, room_id, type, state_key, sender, \
+     origin_server_ts, content, depth, prev_events, auth_events, redacts) \
+     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)";
+
+const SET_CURRENT_STATE_QUERY: &str = "INSERT INTO current_state \
+     (room_id, type, state_key, event_id, membership) VALUES ($1, $2, $3, $4, $5) \
+     ON CONFLICT (room_id, type, state_key) \
+     DO UPDATE SET event_id=EXCLUDED.event_id, membership=EXCLUDED.membership";
+
+const UNSUPPORTED_ROOM_VERSION: ErrorBody = ErrorBody::new_static(
+    error_code::M_UNSUPPORTED_ROOM_VERSION,
+    "The room has a version this server does not support",
+);
+
+/// Locks a room until the end of the transaction, resolving to its version if the room exists.
+///
+/// Anything which appends to a room must hold this lock, so that concurrent events are not
+/// built on the same predecessor.
+pub fn lock_room(
+    db: Client,
+    room_id: String,
+) -> TransactionFutureBox<Option<&'static RoomVersion>> {
+    Box::new(
+        db::lift(db::query_opt(db, LOCK_ROOM_QUERY, params![room_id])).and_then(|(row, db)| {
+            match row {
+                Some(row) => match room_versions::get(row.get(0)) {
+                    Some(version) => Ok((Some(version), db)),
+                    None => Err((UNSUPPORTED_ROOM_VERSION.into(), db)),
+                },
+                None => Ok((None, db)),
+            }
+        }),
+    )
+}
+
+/// Persists `event`, making it part of the room's current state if it is a state event.
+///
+/// This should run inside a transaction, so that the event and the current state are always
+/// updated together.
+pub fn insert_event(db: Client, event: Event) -> TransactionFutureBox<Event> {
+    Box::new(
+        db::lift(db::execute(
+            db,
+            INSERT_EVENT_QUERY,
+            params![
+                event.event_id.clone(),
+                event.room_id.clone(),
+                event.type_.clone(),
+                event.state_key.clone(),
+                event.sender.clone(),
+                event.origin_server_ts,
+                event.content.clone(),
+                event.depth,
+                event.prev_events.clone(),
+                event.auth_events.clone(),
+                event.redacts.clone(),
+            ],
+        ))
+        .and_then(move |(_, db)| -> TransactionFutureBox<Event> {
+            match event.state_key.clone() {
+                Some(state_key) => Box::new(
+                    db::lift(db::execute(
+                        db,
+                        SET_CURRENT_STATE_QUERY,
+                        params![
+                            event.room_id.clone(),
+                            event.type_.clone(),
+                            state_key,
+                            event.event_id.clone(),
+                            event.membership().map(str::to_owned),
+                        ],
+                    ))
+                    .map(move |(_, db)| (event, db)),
+                ),
+                None => Box::new(future::ok((event, db))),
+            }
+        }),
+    )
+}
+
+/// Creates an event on top of the latest event in the room, checks it against the current state
+/// of the room under the rules of its version and persists it.
+///
+/// Events rejected by the authorization rules fail with `M_FORBIDDEN`, as do redactions which
+/// could never take effect.
+pub fn append_event(
+    db: Client,
+    version: &'static RoomVersion,
+    room_id: String,
+    sender: String,
+    type_: String,
+    state_key: Option<String>,
+    mut content: Value,
+) -> TransactionFutureBox<Event> {
+    // Clients name the redacted event in the content, which older versions keep it out of.
+    let redacts = match content.as_object_mut() {
+        Some(content)
+            if type_ == "m.room.redaction" && version.redaction_rules < RedactionRules::V11 =>
+        {
+            content
+                .remove("redacts")
+                .and_then(|redacts| redacts.as_str().map(str::to_owned))
+        }
+        _ => None,
+    };
+
+    Box::new(
+        db::lift(db::query_opt(
+            db,
+            LATEST_EVENT_QUERY,
+            params![room_id.clone()],
+        ))
+        .and_then(move |(latest, db)| {
+            let (prev_events, depth) = match latest {
+                Some(latest) => (vec![latest.get(0)], latest.get::<_, i64>(1) + 1),
+                None => (Vec::new(), 1),
+            };
+
+            get_current_state(db, room_id.clone()).and_then(move |(state, db)| {
+                let mut state = events::state_map(state);
+                let auth_state: StateMap =
+                    events::auth_types(&type_, state_key.as_deref(), &sender, &content)
+                        .into_iter()
+                        .filter_map(|key| Some((key.clone(), state.remove(&key)?)))
+                        .collect();
+
+                let mut event = Event {
+                    event_id: String::new(),
+                    room_id,
+                    type_,
+                    state_key,
+                    sender,
+                    origin_server_ts: events::now_ms(),
+                    content,
+                    redacts,
+                    depth,
+                    prev_events,
+                    auth_events: auth_state
+                        .values()
+                        .map(|event| event.event_id.clone())
+                        .collect(),
+                };
+                event.event_id = version.event_id(&event);
+
+                match event_auth::check(version, &event, &auth_state) {
+                    Ok(()) => Ok(((event, auth_state), db)),
+                    Err(reason) => Err((
+                        ErrorBody::new_static(error_code::M_FORBIDDEN, reason).into(),
+                        db,
+                    )),
+                }
+            })
+        })
+        .and_then(move |((event, auth_state), db)| {
+            check_redacted_event(db, version, event, auth_state)
+        })
+        .and_then(|(event, db)| insert_event(db, event)),
+    )
+}
+
+/// Checks that a redaction may take effect on the event it redacts.
+///
+/// The authorization rules cannot tell this when event IDs do not name a server, and this server
+/// does not apply redactions itself yet, so ones which it never could are refused up front.
+fn check_redacted_event(
+    db: Client,
+    version: &'static RoomVersion,
+    event: Event,
+    auth_state: StateMap,
+) -> TransactionFutureBox<Event> {
+    let redacts = match version.redacts(&event) {
+        Some(redacts)
+            if event.type_ == "m.room.redaction"
+                && version.event_id_format != EventIdFormat::Arbitrary =>
+        {
+            redacts.to_owned()
+        }
+        _ => return Box::new(future::ok((event, db))),
+    };
+
+    Box::new(get_event(db, redacts).and_then(move |(redacted, db)| {
+        let original_sender = redacted
+            .filter(|redacted| redacted.room_id == event.room_id)
+            .map(|redacted| redacted.sender);
+        if event_auth::may_apply_redaction(version, &event, original_sender.as_deref(), &auth_state)
+        {
+            Ok((event, db))
+        } else {
+            Err((
+                ErrorBody::new_static(
+                    error_code::M_FORBIDDEN,
+                    "Insufficient power level to redact",
+                )
+                .into(),
+                db,
+            ))
+        }
+    }))
+}
+
+/// Loads an event by its ID, from any room.
+pub fn get_event(db: Client, event_id: String) -> TransactionFutureBox<Option<Event>> {
+    Box::new(
+        db::lift(db::query_opt(db, EVENT_QUERY, params![event_id]))
+            .map(|(row, db)| (row.as_ref().map(Event::from_row), db)),
+    )
+}
+
+/// Loads every event in the current state of a room.
+pub fn get_current_state(db: Client, room_id: String) -> TransactionFutureBox<Vec<Event>> {
+    Box::new(
+        db::lift(db::query(db, CURRENT_STATE_QUERY, params![room_id]))
+            .map(|(rows, db)| (rows.iter().map(Event::from_row).collect(), db)),
+    )
+}
+
+/// Loads up to `limit` events of a room which come after the stream position `since`, together
+/// with their own stream positions.
+#[allow(dead_code)]
+pub fn get_events_after(
+    db: Client,
+    room_id: String,
+    since: i64,
+    limit: i64,
+) -> TransactionFutureBox<Vec<(i64, Event)>> {
+    Box::new(
+        db::lift(db::query(
+            db,
+            EVENTS_AFTER_QUERY,
+            params![room_id, since, limit],
+        ))
+        .map(|(rows, db)| {
+            let events = rows
+                .iter()
+                .map(|row| (row.get(11), Event::from_row(row)))
+                .collect();
+            (events, db)
+        }),
+    )
+}
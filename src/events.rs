@@ -0,0 +1,195 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A persisted room event, as a node of the room's event graph.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub event_id: String,
+    pub room_id: String,
+    pub type_: String,
+    /// Present exactly for state events; an empty key is still a state event.
+    pub state_key: Option<String>,
+    pub sender: String,
+    pub origin_server_ts: i64,
+    pub content: Value,
+    /// The event redacted by a redaction, in rooms which keep it outside of the content.
+    pub redacts: Option<String>,
+    pub depth: i64,
+    pub prev_events: Vec<String>,
+    pub auth_events: Vec<String>,
+}
+
+impl Event {
+    /// Reads an event from a row whose columns are, in order, `id, room_id, type, state_key,
+    /// sender, origin_server_ts, content, depth, prev_events, auth_events, redacts`.
+    pub fn from_row(row: &tokio_postgres::Row) -> Event {
+        Event {
+            event_id: row.get(0),
+            room_id: row.get(1),
+            type_: row.get(2),
+            state_key: row.get(3),
+            sender: row.get(4),
+            origin_server_ts: row.get(5),
+            content: row.get(6),
+            redacts: row.get(10),
+            depth: row.get(7),
+            prev_events: row.get(8),
+            auth_events: row.get(9),
+        }
+    }
+
+    /// Formats the event the way it is sent to clients.
+    pub fn to_client_json(&self) -> Value {
+        let mut event = json!({
+            "event_id": self.event_id,
+            "room_id": self.room_id,
+            "type": self.type_,
+            "sender": self.sender,
+            "origin_server_ts": self.origin_server_ts,
+            "content": self.content,
+            "unsigned": {},
+        });
+        if let Some(ref state_key) = self.state_key {
+            event["state_key"] = json!(state_key);
+        }
+        if let Some(ref redacts) = self.redacts {
+            event["redacts"] = json!(redacts);
+        }
+
+        event
+    }
+
+    /// The membership set by an `m.room.member` event.
+    pub fn membership(&self) -> Option<&str> {
+        if self.type_ == "m.room.member" {
+            self.content["membership"].as_str()
+        } else {
+            None
+        }
+    }
+}
+
+/// The state of a room, keyed by event type and state key.
+pub type StateMap = HashMap<(String, String), Event>;
+
+pub fn get_state<'a>(state: &'a StateMap, type_: &str, state_key: &str) -> Option<&'a Event> {
+    state.get(&(type_.to_owned(), state_key.to_owned()))
+}
+
+/// Collects state events into a `StateMap`, ignoring any events which are not state events.
+pub fn state_map(events: Vec<Event>) -> StateMap {
+    events
+        .into_iter()
+        .filter_map(|event| Some(((event.type_.clone(), event.state_key.clone()?), event)))
+        .collect()
+}
+
+/// Encodes `value` as canonical JSON, with sorted keys and no insignificant whitespace.
+pub fn canonical_json(value: &Value) -> String {
+    // Objects are backed by sorted maps, so serde_json already emits their keys in order.
+    value.to_string()
+}
+
+pub fn generate_event_id(server_name: &str) -> String {
+    format!("${}:{}", uuid::Uuid::new_v4().to_simple(), server_name)
+}
+
+pub fn now_ms() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() as i64 * 1000 + i64::from(now.subsec_millis())
+}
+
+/// Lists the `(type, state_key)` pairs of the state events which authorise an event.
+pub fn auth_types(
+    type_: &str,
+    state_key: Option<&str>,
+    sender: &str,
+    content: &Value,
+) -> Vec<(String, String)> {
+    if type_ == "m.room.create" {
+        return Vec::new();
+    }
+
+    let mut auth_types = vec![
+        ("m.room.create".to_owned(), String::new()),
+        ("m.room.power_levels".to_owned(), String::new()),
+        ("m.room.member".to_owned(), sender.to_owned()),
+    ];
+
+    if let ("m.room.member", Some(state_key)) = (type_, state_key) {
+        if state_key != sender {
+            auth_types.push(("m.room.member".to_owned(), state_key.to_owned()));
+        }
+
+        let membership = content["membership"].as_str();
+        if let Some("join") | Some("invite") | Some("knock") = membership {
+            auth_types.push(("m.room.join_rules".to_owned(), String::new()));
+        }
+        if let (Some("invite"), Some(token)) = (
+            membership,
+            content["third_party_invite"]["signed"]["token"].as_str(),
+        ) {
+            auth_types.push(("m.room.third_party_invite".to_owned(), token.to_owned()));
+        }
+    }
+
+    auth_types
+}
+
+#[cfg(test)]
+mod tests {
+    use super::auth_types;
+    use serde_json::json;
+
+    fn pairs(auth_types: &[(String, String)]) -> Vec<(&str, &str)> {
+        auth_types
+            .iter()
+            .map(|(type_, state_key)| (type_.as_str(), state_key.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn auth_types_for_events() {
+        assert!(auth_types("m.room.create", Some(""), "@a:x", &json!({})).is_empty());
+
+        assert_eq!(
+            pairs(&auth_types("m.room.message", None, "@a:x", &json!({}))),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.power_levels", ""),
+                ("m.room.member", "@a:x"),
+            ]
+        );
+
+        assert_eq!(
+            pairs(&auth_types(
+                "m.room.member",
+                Some("@b:x"),
+                "@a:x",
+                &json!({ "membership": "invite" })
+            )),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.power_levels", ""),
+                ("m.room.member", "@a:x"),
+                ("m.room.member", "@b:x"),
+                ("m.room.join_rules", ""),
+            ]
+        );
+
+        assert_eq!(
+            pairs(&auth_types(
+                "m.room.member",
+                Some("@a:x"),
+                "@a:x",
+                &json!({ "membership": "leave" })
+            )),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.power_levels", ""),
+                ("m.room.member", "@a:x"),
+            ]
+        );
+    }
+}
@@ -1,13 +1,33 @@
+#[macro_use]
+mod db;
+
+mod account_data;
+mod authentication;
+mod device_management;
+mod event_auth;
+mod event_storage;
+mod events;
+mod power_levels;
+mod profile;
+mod room_creation;
+mod room_membership;
+mod room_participation;
+mod room_versions;
 mod server_administration;
 mod session_management;
+mod state_resolution;
 mod user_data;
+mod user_id;
+mod user_interactive_auth;
 
 use futures::future;
-use hyper::rt::Future;
-use hyper::service::Service;
+use hyper::rt::{Future, Stream};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, Service};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use serde_json::json;
 use std::borrow::Cow;
+use std::fmt;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -18,8 +38,17 @@ type DbPool = bb8::Pool<bb8_postgres::PostgresConnectionManager<tokio_postgres::
 mod error_code {
     pub const CHAT_LOMATIA_INVALID_PARAM: &str = "CHAT_LOMATIA_INVALID_PARAM";
     pub const CHAT_LOMATIA_INTERNAL_ERROR: &str = "CHAT_LOMATIA_INTERNAL_ERROR";
+    pub const M_EXCLUSIVE: &str = "M_EXCLUSIVE";
     pub const M_FORBIDDEN: &str = "M_FORBIDDEN";
+    pub const M_INVALID_PARAM: &str = "M_INVALID_PARAM";
+    pub const M_MISSING_PARAM: &str = "M_MISSING_PARAM";
+    pub const M_MISSING_TOKEN: &str = "M_MISSING_TOKEN";
+    pub const M_NOT_FOUND: &str = "M_NOT_FOUND";
     pub const M_UNKNOWN: &str = "M_UNKNOWN";
+    pub const M_UNKNOWN_TOKEN: &str = "M_UNKNOWN_TOKEN";
+    pub const M_UNSUPPORTED_ROOM_VERSION: &str = "M_UNSUPPORTED_ROOM_VERSION";
+    pub const M_USER_DEACTIVATED: &str = "M_USER_DEACTIVATED";
+    pub const M_USER_IN_USE: &str = "M_USER_IN_USE";
 }
 
 #[derive(Debug)]
@@ -52,6 +81,9 @@ impl ErrorBody {
         let mut resp = Response::new(Body::from(self.to_string()));
         *resp.status_mut() = match self.errcode {
             error_code::CHAT_LOMATIA_INTERNAL_ERROR => StatusCode::INTERNAL_SERVER_ERROR,
+            error_code::M_MISSING_TOKEN | error_code::M_UNKNOWN_TOKEN => StatusCode::UNAUTHORIZED,
+            error_code::M_FORBIDDEN | error_code::M_USER_DEACTIVATED => StatusCode::FORBIDDEN,
+            error_code::M_NOT_FOUND => StatusCode::NOT_FOUND,
             _ => StatusCode::BAD_REQUEST,
         };
         resp.headers_mut().insert(
@@ -62,13 +94,13 @@ impl ErrorBody {
         resp
     }
 }
-impl ToString for ErrorBody {
-    fn to_string(&self) -> String {
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         json!({
             "errcode": self.errcode,
             "error": self.error
         })
-        .to_string()
+        .fmt(f)
     }
 }
 
@@ -80,6 +112,8 @@ pub enum Error {
     CanceledFuture,
     Hyper(hyper::Error),
     UserFacing(ErrorBody),
+    /// User-interactive authentication is incomplete; holds the progress to send to the client.
+    UserInteractiveAuth(serde_json::Value),
 }
 
 impl From<futures::Canceled> for Error {
@@ -100,6 +134,15 @@ impl From<bb8::RunError<tokio_postgres::Error>> for Error {
     }
 }
 
+impl From<bb8::RunError<Error>> for Error {
+    fn from(err: bb8::RunError<Error>) -> Error {
+        match err {
+            bb8::RunError::User(err) => err,
+            bb8::RunError::TimedOut => Error::DBPool(bb8::RunError::TimedOut),
+        }
+    }
+}
+
 impl From<ErrorBody> for Error {
     fn from(err: ErrorBody) -> Error {
         Error::UserFacing(err)
@@ -118,7 +161,34 @@ impl From<bcrypt::BcryptError> for Error {
     }
 }
 
-const APPLICATION_JSON: &'static str = "application/json";
+const APPLICATION_JSON: &str = "application/json";
+
+fn json_response(body: serde_json::Value) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body.to_string()));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static(APPLICATION_JSON),
+    );
+
+    resp
+}
+
+fn parse_json_body<T>(body: Body) -> impl Future<Item = T, Error = Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    body.concat2().map_err(Error::from).and_then(|body| {
+        serde_json::from_slice(&body).map_err(|err| {
+            match err.classify() {
+                serde_json::error::Category::Syntax
+                | serde_json::error::Category::Eof
+                | serde_json::error::Category::Io => ErrorBody::NOT_JSON,
+                serde_json::error::Category::Data => ErrorBody::BAD_JSON,
+            }
+            .into()
+        })
+    })
+}
 
 fn tack_on<T, E, A>(res: Result<T, E>, addition: A) -> Result<(T, A), (E, A)> {
     match res {
@@ -127,10 +197,34 @@ fn tack_on<T, E, A>(res: Result<T, E>, addition: A) -> Result<(T, A), (E, A)> {
     }
 }
 
+#[derive(Clone)]
 pub struct LMServer {
     cpupool: Arc<futures_cpupool::CpuPool>,
     db_pool: DbPool,
-    hostname: Arc<String>,
+    server_name: Arc<String>,
+    remote_addr: SocketAddr,
+    /// Patterns matching localparts which may not be registered.
+    reserved_localparts: Arc<Vec<regex::Regex>>,
+    /// Whether the user directory includes users who do not share a room with the searcher.
+    user_directory_search_all: bool,
+}
+
+impl LMServer {
+    /// Resolves the access token presented with `req` before handing the request to `handler`.
+    ///
+    /// Requests without a valid token are answered with `M_MISSING_TOKEN` or `M_UNKNOWN_TOKEN`.
+    fn authenticated<F>(&self, req: Request<Body>, handler: F) -> EndpointFutureBox
+    where
+        F: FnOnce(&LMServer, authentication::AuthContext, Request<Body>) -> EndpointFutureBox
+            + Send
+            + 'static,
+    {
+        let server = self.clone();
+        Box::new(
+            authentication::authenticate(&self.db_pool, &req, self.remote_addr.ip())
+                .and_then(move |auth| handler(&server, auth, req)),
+        )
+    }
 }
 
 impl Service for LMServer {
@@ -141,12 +235,280 @@ impl Service for LMServer {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         println!("{} {}", req.method(), req.uri().path());
+
+        let segments: Vec<String> = req.uri().path()[1..]
+            .split('/')
+            .map(|segment| {
+                percent_encoding::percent_decode(segment.as_bytes())
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
         Box::new(
-            match (req.method(), req.uri().path()) {
-                (&Method::GET, "/_matrix/client/versions") => server_administration::versions(),
-                (&Method::POST, "/_matrix/client/r0/register") => user_data::register(self, req),
-                // (&Method::GET, "/_matrix/client/r0/login") => session_management::login_opts(),
-                (&Method::POST, "/_matrix/client/r0/login") => session_management::login(self, req),
+            match (req.method(), &segments[..]) {
+                (&Method::GET, ["_matrix", "client", "versions"]) => {
+                    server_administration::versions()
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "capabilities"]) => {
+                    self.authenticated(req, server_administration::capabilities)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "register"]) => {
+                    user_data::register(self, req)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "register", "available"]) => {
+                    user_data::register_available(self, req)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "login"]) => {
+                    session_management::login_opts()
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "account", "deactivate"]) => {
+                    self.authenticated(req, user_data::deactivate)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "account", "password"]) => {
+                    self.authenticated(req, user_data::change_password)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "account", "whoami"]) => {
+                    self.authenticated(req, user_data::whoami)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "delete_devices"]) => {
+                    self.authenticated(req, device_management::delete_devices)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "devices"]) => {
+                    self.authenticated(req, device_management::get_devices)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "devices", device_id]) => {
+                    let device_id = device_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        device_management::get_device(server, auth, req, device_id)
+                    })
+                }
+                (&Method::PUT, ["_matrix", "client", "r0", "devices", device_id]) => {
+                    let device_id = device_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        device_management::update_device(server, auth, req, device_id)
+                    })
+                }
+                (&Method::DELETE, ["_matrix", "client", "r0", "devices", device_id]) => {
+                    let device_id = device_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        device_management::delete_device(server, auth, req, device_id)
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "profile", user_id]) => {
+                    profile::get_profile(self, req, user_id.to_string())
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "profile", user_id, "displayname"]) => {
+                    profile::get_profile_field(
+                        self,
+                        req,
+                        user_id.to_string(),
+                        &profile::DISPLAYNAME,
+                    )
+                }
+                (&Method::PUT, ["_matrix", "client", "r0", "profile", user_id, "displayname"]) => {
+                    let user_id = user_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        profile::set_profile_field(
+                            server,
+                            auth,
+                            req,
+                            user_id,
+                            &profile::DISPLAYNAME,
+                        )
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "profile", user_id, "avatar_url"]) => {
+                    profile::get_profile_field(self, req, user_id.to_string(), &profile::AVATAR_URL)
+                }
+                (&Method::PUT, ["_matrix", "client", "r0", "profile", user_id, "avatar_url"]) => {
+                    let user_id = user_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        profile::set_profile_field(server, auth, req, user_id, &profile::AVATAR_URL)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "user_directory", "search"]) => {
+                    self.authenticated(req, profile::search_user_directory)
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "user", user_id, "account_data", type_]) => {
+                    let (user_id, type_) = (user_id.to_string(), type_.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::get_account_data(server, auth, req, user_id, None, type_)
+                    })
+                }
+                (&Method::PUT, ["_matrix", "client", "r0", "user", user_id, "account_data", type_]) => {
+                    let (user_id, type_) = (user_id.to_string(), type_.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::set_account_data(server, auth, req, user_id, None, type_)
+                    })
+                }
+                (
+                    &Method::GET,
+                    ["_matrix", "client", "r0", "user", user_id, "rooms", room_id, "account_data", type_],
+                ) => {
+                    let (user_id, room_id, type_) =
+                        (user_id.to_string(), room_id.to_string(), type_.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::get_account_data(server, auth, req, user_id, Some(room_id), type_)
+                    })
+                }
+                (
+                    &Method::PUT,
+                    ["_matrix", "client", "r0", "user", user_id, "rooms", room_id, "account_data", type_],
+                ) => {
+                    let (user_id, room_id, type_) =
+                        (user_id.to_string(), room_id.to_string(), type_.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::set_account_data(server, auth, req, user_id, Some(room_id), type_)
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "user", user_id, "rooms", room_id, "tags"]) => {
+                    let (user_id, room_id) = (user_id.to_string(), room_id.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::get_tags(server, auth, req, user_id, room_id)
+                    })
+                }
+                (
+                    &Method::PUT,
+                    ["_matrix", "client", "r0", "user", user_id, "rooms", room_id, "tags", tag],
+                ) => {
+                    let (user_id, room_id, tag) =
+                        (user_id.to_string(), room_id.to_string(), tag.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::set_tag(server, auth, req, user_id, room_id, tag)
+                    })
+                }
+                (
+                    &Method::DELETE,
+                    ["_matrix", "client", "r0", "user", user_id, "rooms", room_id, "tags", tag],
+                ) => {
+                    let (user_id, room_id, tag) =
+                        (user_id.to_string(), room_id.to_string(), tag.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        account_data::delete_tag(server, auth, req, user_id, room_id, tag)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "createRoom"]) => {
+                    self.authenticated(req, room_creation::create_room)
+                }
+                (
+                    &Method::PUT,
+                    ["_matrix", "client", "r0", "rooms", room_id, "send", event_type, txn_id],
+                ) => {
+                    let (room_id, event_type, txn_id) =
+                        (room_id.to_string(), event_type.to_string(), txn_id.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::send_message(server, auth, req, room_id, event_type, txn_id)
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "rooms", room_id, "state"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::get_state(server, auth, req, room_id)
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "rooms", room_id, "state", event_type]) => {
+                    let (room_id, event_type) = (room_id.to_string(), event_type.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::get_state_event(server, auth, req, room_id, event_type, String::new())
+                    })
+                }
+                (
+                    &Method::GET,
+                    ["_matrix", "client", "r0", "rooms", room_id, "state", event_type, state_key],
+                ) => {
+                    let (room_id, event_type, state_key) =
+                        (room_id.to_string(), event_type.to_string(), state_key.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::get_state_event(server, auth, req, room_id, event_type, state_key)
+                    })
+                }
+                (&Method::PUT, ["_matrix", "client", "r0", "rooms", room_id, "state", event_type]) => {
+                    let (room_id, event_type) = (room_id.to_string(), event_type.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::send_state_event(server, auth, req, room_id, event_type, String::new())
+                    })
+                }
+                (
+                    &Method::PUT,
+                    ["_matrix", "client", "r0", "rooms", room_id, "state", event_type, state_key],
+                ) => {
+                    let (room_id, event_type, state_key) =
+                        (room_id.to_string(), event_type.to_string(), state_key.to_string());
+                    self.authenticated(req, move |server, auth, req| {
+                        room_participation::send_state_event(server, auth, req, room_id, event_type, state_key)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "upgrade"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_creation::upgrade_room(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "join"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::join(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "leave"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::leave(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "invite"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::invite(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "kick"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::kick(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "ban"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::ban(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "unban"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::unban(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "rooms", room_id, "forget"]) => {
+                    let room_id = room_id.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::forget(server, auth, req, room_id)
+                    })
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "join", room_id_or_alias]) => {
+                    let room_id_or_alias = room_id_or_alias.to_string();
+                    self.authenticated(req, move |server, auth, req| {
+                        room_membership::join_by_id_or_alias(server, auth, req, room_id_or_alias)
+                    })
+                }
+                (&Method::GET, ["_matrix", "client", "r0", "joined_rooms"]) => {
+                    self.authenticated(req, room_membership::joined_rooms)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "login"]) => {
+                    session_management::login(self, req)
+                }
+                (&Method::POST, ["_matrix", "client", "v1", "login", "get_token"]) => {
+                    self.authenticated(req, session_management::get_login_token)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "logout"]) => {
+                    self.authenticated(req, session_management::logout)
+                }
+                (&Method::POST, ["_matrix", "client", "r0", "logout", "all"]) => {
+                    self.authenticated(req, session_management::logout_all)
+                }
                 _ => {
                     let mut response =
                         Response::new(Body::from(ErrorBody::UNRECOGNIZED.to_string()));
@@ -158,10 +520,14 @@ impl Service for LMServer {
                     Box::new(future::ok(response))
                 }
             }
-            .or_else(|err| {
-                if let Error::UserFacing(err) = err {
-                    Ok(err.to_response())
-                } else {
+            .or_else(|err| match err {
+                Error::UserFacing(err) => Ok(err.to_response()),
+                Error::UserInteractiveAuth(body) => {
+                    let mut resp = json_response(body);
+                    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+                    Ok(resp)
+                }
+                err => {
                     eprintln!("{:?}", err);
                     Ok(ErrorBody::INTERNAL_ERROR.to_response())
                 }
@@ -190,6 +556,29 @@ fn main() {
                 .takes_value(true)
                 .default_value("8448"),
         )
+        .arg(
+            clap::Arg::with_name("server-name")
+                .long("server-name")
+                .help("Sets the server name used in user IDs, which may differ from the address")
+                .takes_value(true)
+                .env("SERVER_NAME")
+                .default_value("localhost"),
+        )
+        .arg(
+            clap::Arg::with_name("reserved-localpart")
+                .long("reserved-localpart")
+                .help("Reserves localparts matching a pattern, e.g. 'admin' or '_irc_.*'")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            clap::Arg::with_name("user-directory-search-all")
+                .long("user-directory-search-all")
+                .help(
+                    "Lets the user directory find all local users, not just those sharing a room",
+                ),
+        )
         .arg(
             clap::Arg::with_name("database-url")
                 .long("database-url")
@@ -205,7 +594,23 @@ fn main() {
     let socket_addr = SocketAddr::new(ip_address, port);
     let cpupool = Arc::new(futures_cpupool::Builder::new().create());
     let db_params = matches.value_of("database-url").unwrap().to_owned();
-    let hostname = Arc::new(socket_addr.to_string().to_owned());
+    let server_name = matches.value_of("server-name").unwrap().to_owned();
+    if !user_id::is_valid_server_name(&server_name) {
+        panic!("Invalid server name: {}", server_name);
+    }
+    let server_name = Arc::new(server_name);
+    let user_directory_search_all = matches.is_present("user-directory-search-all");
+    let reserved_localparts = Arc::new(
+        matches
+            .values_of("reserved-localpart")
+            .into_iter()
+            .flatten()
+            .map(|pattern| {
+                regex::Regex::new(&format!("^(?:{})$", pattern))
+                    .unwrap_or_else(|err| panic!("Invalid reserved localpart pattern: {}", err))
+            })
+            .collect::<Vec<_>>(),
+    );
 
     tokio::run(
         futures::future::lazy(move || {
@@ -218,15 +623,18 @@ fn main() {
                 .and_then(move |db_pool| {
                     println!("Listening on http://{}...", socket_addr);
 
-                    Server::bind(&socket_addr.to_owned()).serve(
-                        move || -> future::FutureResult<LMServer, hyper::Error> {
+                    Server::bind(&socket_addr.to_owned()).serve(make_service_fn(
+                        move |socket: &AddrStream| -> future::FutureResult<LMServer, hyper::Error> {
                             future::ok(LMServer {
                                 cpupool: cpupool.clone(),
                                 db_pool: db_pool.clone(),
-                                hostname: hostname.clone(),
+                                server_name: server_name.clone(),
+                                remote_addr: socket.remote_addr(),
+                                reserved_localparts: reserved_localparts.clone(),
+                                user_directory_search_all,
                             })
                         },
-                    )
+                    ))
                 })
         })
         .map_err(|err| panic!("Server encountered a runtime error: {:?}", err)),
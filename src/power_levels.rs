@@ -0,0 +1,168 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The power levels of a room, as set by its `m.room.power_levels` event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerLevels {
+    pub users: HashMap<String, i64>,
+    pub users_default: i64,
+    pub events: HashMap<String, i64>,
+    pub events_default: i64,
+    pub state_default: i64,
+    pub ban: i64,
+    pub kick: i64,
+    pub invite: i64,
+    pub redact: i64,
+}
+
+/// Reads a power level, which older rooms may have stored as a string.
+pub fn parse_level(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(level) => level.as_i64(),
+        Value::String(level) => level.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_levels(value: &Value) -> HashMap<String, i64> {
+    value
+        .as_object()
+        .map(|levels| {
+            levels
+                .iter()
+                .filter_map(|(key, level)| Some((key.clone(), parse_level(level)?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Rewrites the content of an `m.room.power_levels` event with any levels stored as strings
+/// replaced by integers, which newer room versions require.
+pub fn integer_content(content: &Value) -> Value {
+    let to_integer = |level: &mut Value| {
+        if let (Value::String(_), Some(parsed)) = (&level, parse_level(level)) {
+            *level = Value::from(parsed);
+        }
+    };
+
+    let mut content = content.clone();
+    if let Value::Object(ref mut content) = content {
+        for (key, value) in content.iter_mut() {
+            match (key.as_str(), value) {
+                ("users", Value::Object(levels))
+                | ("events", Value::Object(levels))
+                | ("notifications", Value::Object(levels)) => {
+                    levels.values_mut().for_each(to_integer)
+                }
+                (_, Value::Object(_)) => {}
+                (_, level) => to_integer(level),
+            }
+        }
+    }
+
+    content
+}
+
+impl PowerLevels {
+    /// Reads the content of an `m.room.power_levels` event, filling in the defaults for any
+    /// missing key.
+    pub fn from_content(content: &Value) -> PowerLevels {
+        let level = |key: &str, default: i64| parse_level(&content[key]).unwrap_or(default);
+
+        PowerLevels {
+            users: parse_levels(&content["users"]),
+            users_default: level("users_default", 0),
+            events: parse_levels(&content["events"]),
+            events_default: level("events_default", 0),
+            state_default: level("state_default", 50),
+            ban: level("ban", 50),
+            kick: level("kick", 50),
+            invite: level("invite", 0),
+            redact: level("redact", 50),
+        }
+    }
+
+    /// The power levels of a room without an `m.room.power_levels` event, where only its creator
+    /// is privileged.
+    pub fn without_event(creator: Option<&str>) -> PowerLevels {
+        let mut users = HashMap::new();
+        if let Some(creator) = creator {
+            users.insert(creator.to_owned(), 100);
+        }
+
+        PowerLevels {
+            users,
+            users_default: 0,
+            events: HashMap::new(),
+            events_default: 0,
+            state_default: 0,
+            ban: 50,
+            kick: 50,
+            invite: 0,
+            redact: 50,
+        }
+    }
+
+    pub fn user_level(&self, user_id: &str) -> i64 {
+        self.users
+            .get(user_id)
+            .cloned()
+            .unwrap_or(self.users_default)
+    }
+
+    /// The level required to send an event of type `event_type`.
+    pub fn event_level(&self, event_type: &str, is_state: bool) -> i64 {
+        match self.events.get(event_type) {
+            Some(&level) => level,
+            None if is_state => self.state_default,
+            None => self.events_default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PowerLevels;
+    use serde_json::json;
+
+    #[test]
+    fn levels() {
+        let power_levels = PowerLevels::from_content(&json!({
+            "users": { "@alice:x": 100, "@bob:x": "75" },
+            "events": { "m.room.name": 25 },
+            "events_default": 10,
+        }));
+
+        assert_eq!(power_levels.user_level("@alice:x"), 100);
+        assert_eq!(power_levels.user_level("@bob:x"), 75);
+        assert_eq!(power_levels.user_level("@carol:x"), 0);
+        assert_eq!(power_levels.event_level("m.room.name", true), 25);
+        assert_eq!(power_levels.event_level("m.room.topic", true), 50);
+        assert_eq!(power_levels.event_level("m.room.message", false), 10);
+        assert_eq!(power_levels.ban, 50);
+
+        let power_levels = PowerLevels::without_event(Some("@alice:x"));
+        assert_eq!(power_levels.user_level("@alice:x"), 100);
+        assert_eq!(power_levels.event_level("m.room.topic", true), 0);
+    }
+
+    #[test]
+    fn integer_content() {
+        assert_eq!(
+            super::integer_content(&json!({
+                "users": { "@alice:x": "100", "@bob:x": 50 },
+                "events": { "m.room.name": " 25" },
+                "notifications": { "room": "50" },
+                "ban": "75",
+                "kick": "high",
+            })),
+            json!({
+                "users": { "@alice:x": 100, "@bob:x": 50 },
+                "events": { "m.room.name": 25 },
+                "notifications": { "room": 50 },
+                "ban": 75,
+                "kick": "high",
+            })
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use futures::{future, Future};
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use crate::authentication::AuthContext;
+use crate::db;
+use crate::user_id::UserId;
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+const PROFILE_QUERY: &str = "SELECT profiles.displayname, profiles.avatar_url FROM profiles \
+                             INNER JOIN users ON users.id = profiles.user_id \
+                             WHERE users.localpart=$1";
+
+pub const NEW_PROFILE_QUERY: &str = "INSERT INTO profiles (user_id, displayname) VALUES ($1, $2)";
+
+// Only clears the profile when `$2` is true, so that it can run unconditionally.
+pub const ERASE_PROFILE_QUERY: &str =
+    "UPDATE profiles SET displayname=NULL, avatar_url=NULL WHERE user_id=$1 AND $2";
+
+// Localparts are split on punctuation, so that e.g. "smith" finds "alice.smith".
+const SEARCH_ALL_QUERY: &str = "SELECT users.localpart, profiles.displayname, profiles.avatar_url \
+     FROM users INNER JOIN profiles ON profiles.user_id = users.id \
+     WHERE NOT users.deactivated \
+     AND (to_tsvector('simple', regexp_replace(users.localpart, '[^a-z0-9]+', ' ', 'g')) \
+     @@ to_tsquery('simple', $1) \
+     OR to_tsvector('simple', coalesce(profiles.displayname, '')) @@ to_tsquery('simple', $1)) \
+     ORDER BY users.localpart LIMIT $2";
+
+// `$3` is the searcher's user ID and `$4` the server name.
+const SEARCH_SHARED_QUERY: &str = "SELECT users.localpart, profiles.displayname, \
+     profiles.avatar_url \
+     FROM users INNER JOIN profiles ON profiles.user_id = users.id \
+     WHERE NOT users.deactivated \
+     AND EXISTS (SELECT 1 FROM current_state AS searcher INNER JOIN current_state AS member \
+     ON member.room_id = searcher.room_id \
+     WHERE searcher.type = 'm.room.member' AND searcher.state_key = $3 \
+     AND searcher.membership = 'join' \
+     AND member.type = 'm.room.member' AND member.state_key = '@' || users.localpart || ':' || $4::text \
+     AND member.membership = 'join') \
+     AND (to_tsvector('simple', regexp_replace(users.localpart, '[^a-z0-9]+', ' ', 'g')) \
+     @@ to_tsquery('simple', $1) \
+     OR to_tsvector('simple', coalesce(profiles.displayname, '')) @@ to_tsquery('simple', $1)) \
+     ORDER BY users.localpart LIMIT $2";
+
+const DEFAULT_SEARCH_LIMIT: i64 = 10;
+
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+const PROFILE_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "Profile not found");
+const NOT_OWN_PROFILE: ErrorBody = ErrorBody::new_static(
+    error_code::M_FORBIDDEN,
+    "Cannot change the profile of another user",
+);
+
+/// A single field of a user's profile, which can be read and written on its own.
+pub struct ProfileField {
+    name: &'static str,
+    /// The column holding the field in `PROFILE_QUERY`.
+    column: usize,
+    update_query: &'static str,
+}
+
+pub const DISPLAYNAME: ProfileField = ProfileField {
+    name: "displayname",
+    column: 0,
+    update_query: "UPDATE profiles SET displayname=$2 WHERE user_id=$1",
+};
+
+pub const AVATAR_URL: ProfileField = ProfileField {
+    name: "avatar_url",
+    column: 1,
+    update_query: "UPDATE profiles SET avatar_url=$2 WHERE user_id=$1",
+};
+
+/// Looks up the profile of a local user.
+fn load_profile(
+    server: &LMServer,
+    user_id: &str,
+) -> Box<dyn Future<Item = tokio_postgres::Row, Error = crate::Error> + Send> {
+    let user_id = match UserId::parse(user_id) {
+        Ok(ref user_id) if user_id.server_name() == server.server_name.as_str() => user_id.clone(),
+        // Profiles of users on other servers would have to be fetched over federation.
+        Ok(_) => return Box::new(future::err(PROFILE_NOT_FOUND.into())),
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| {
+                db::query_opt(db, PROFILE_QUERY, params![user_id.localpart().to_owned()])
+            })
+            .map_err(crate::Error::from)
+            .and_then(|row| Ok(row.ok_or(PROFILE_NOT_FOUND)?)),
+    )
+}
+
+pub fn get_profile(server: &LMServer, _req: Request<Body>, user_id: String) -> EndpointFutureBox {
+    Box::new(load_profile(server, &user_id).map(|row| {
+        json_response(json!({
+            DISPLAYNAME.name: row.get::<_, Option<String>>(DISPLAYNAME.column),
+            AVATAR_URL.name: row.get::<_, Option<String>>(AVATAR_URL.column),
+        }))
+    }))
+}
+
+pub fn get_profile_field(
+    server: &LMServer,
+    _req: Request<Body>,
+    user_id: String,
+    field: &'static ProfileField,
+) -> EndpointFutureBox {
+    Box::new(load_profile(server, &user_id).map(move |row| {
+        json_response(json!({ field.name: row.get::<_, Option<String>>(field.column) }))
+    }))
+}
+
+/// Changes a field of the calling user's own profile.
+pub fn set_profile_field(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    user_id: String,
+    field: &'static ProfileField,
+) -> EndpointFutureBox {
+    let own_user_id = UserId::new(&auth.localpart, &server.server_name);
+    match UserId::parse(&user_id) {
+        Ok(ref user_id) if *user_id == own_user_id => {}
+        Ok(_) => return Box::new(future::err(NOT_OWN_PROFILE.into())),
+        Err(err) => return Box::new(future::err(err.into())),
+    }
+
+    let db_pool = server.db_pool.clone();
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |body: serde_json::Value| {
+                let value = match &body[field.name] {
+                    serde_json::Value::Null => None,
+                    serde_json::Value::String(value) => Some(value.clone()),
+                    _ => return Err(ErrorBody::BAD_JSON.into()),
+                };
+
+                Ok(value)
+            })
+            .and_then(move |value| {
+                db_pool
+                    .run(move |db| {
+                        db::execute(db, field.update_query, params![auth.user_id, value])
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+/// Builds a full text query matching every word of `search_term` as a prefix.
+fn search_query(search_term: &str) -> String {
+    search_term
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" & ")
+}
+
+#[derive(Deserialize)]
+struct UserDirectorySearchReqBody {
+    search_term: String,
+    limit: Option<i64>,
+}
+
+/// Searches local users by localpart and display name.
+///
+/// Unless the server is configured to search all users, only users who share a room with the
+/// searcher are returned.
+pub fn search_user_directory(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let server_name = server.server_name.clone();
+    let search_all_users = server.user_directory_search_all;
+
+    Box::new(parse_json_body(req.into_body()).and_then(
+        move |body: UserDirectorySearchReqBody| -> EndpointFutureBox {
+            let query = search_query(&body.search_term);
+            if query.is_empty() {
+                return Box::new(future::ok(json_response(json!({
+                    "results": [],
+                    "limited": false,
+                }))));
+            }
+            let limit = body
+                .limit
+                .unwrap_or(DEFAULT_SEARCH_LIMIT)
+                .clamp(0, MAX_SEARCH_LIMIT);
+            let searcher = UserId::new(&auth.localpart, &server_name).to_string();
+            let local_server_name = server_name.to_string();
+
+            Box::new(
+                db_pool
+                    .run(move |db| {
+                        // One extra row is fetched to find out whether the results were limited.
+                        if search_all_users {
+                            db::query(db, SEARCH_ALL_QUERY, params![query, limit + 1])
+                        } else {
+                            db::query(
+                                db,
+                                SEARCH_SHARED_QUERY,
+                                params![query, limit + 1, searcher, local_server_name],
+                            )
+                        }
+                    })
+                    .map_err(crate::Error::from)
+                    .map(move |rows| {
+                        let results: Vec<_> = rows
+                            .iter()
+                            .take(limit as usize)
+                            .map(|row| {
+                                json!({
+                                    "user_id": UserId::new(row.get(0), &server_name).to_string(),
+                                    "display_name": row.get::<_, Option<String>>(1),
+                                    "avatar_url": row.get::<_, Option<String>>(2),
+                                })
+                            })
+                            .collect();
+
+                        json_response(json!({
+                            "results": results,
+                            "limited": rows.len() as i64 > limit,
+                        }))
+                    }),
+            )
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::search_query;
+
+    #[test]
+    fn search_query_words() {
+        assert_eq!(search_query("Alice"), "alice:*");
+        assert_eq!(
+            search_query(" alice.smith  o'brien"),
+            "alice:* & smith:* & o:* & brien:*"
+        );
+        assert_eq!(search_query("!&|:*"), "");
+    }
+}
@@ -0,0 +1,872 @@
+use futures::{future, stream, Future, Stream};
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+use std::cmp;
+use tokio_postgres::Client;
+
+use crate::authentication::AuthContext;
+use crate::db::{self, TransactionFutureBox};
+use crate::event_storage;
+use crate::events::{self, Event, StateMap};
+use crate::power_levels::{self, PowerLevels};
+use crate::room_membership::{join_content, PROFILE_QUERY};
+use crate::room_participation;
+use crate::room_versions::{self, RoomVersion};
+use crate::user_id::UserId;
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+const NEW_ROOM_QUERY: &str = "INSERT INTO rooms (id, version, creator, is_public, created) \
+                              VALUES ($1, $2, $3, $4, localtimestamp)";
+
+// Takes a room out of the directory, resolving to whether it was in it.
+const UNPUBLISH_ROOM_QUERY: &str = "UPDATE rooms SET is_public=false \
+     FROM (SELECT id, is_public FROM rooms WHERE id=$1) AS old \
+     WHERE rooms.id = old.id RETURNING old.is_public";
+
+/// The state copied from a room into its replacement, besides its power levels.
+const UPGRADED_STATE_TYPES: &[&str] = &[
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    "m.room.encryption",
+    "m.room.server_acl",
+];
+
+const ROOM_ALIASES_UNSUPPORTED: ErrorBody = ErrorBody::new_static(
+    error_code::M_INVALID_PARAM,
+    "Room aliases are not supported",
+);
+const UNSUPPORTED_ROOM_VERSION: ErrorBody = ErrorBody::new_static(
+    error_code::M_UNSUPPORTED_ROOM_VERSION,
+    "Unsupported room version",
+);
+const INVALID_INITIAL_STATE: ErrorBody = ErrorBody::new_static(
+    error_code::M_INVALID_PARAM,
+    "initial_state may not contain create or membership events",
+);
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Visibility {
+    Public,
+    Private,
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+enum Preset {
+    #[serde(rename = "private_chat")]
+    Private,
+    #[serde(rename = "public_chat")]
+    Public,
+    #[serde(rename = "trusted_private_chat")]
+    TrustedPrivate,
+}
+
+/// A state event sent while setting up a room.
+#[derive(Deserialize)]
+struct StateEvent {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(default)]
+    state_key: String,
+    content: Value,
+}
+
+impl StateEvent {
+    fn new(type_: &str, state_key: &str, content: Value) -> StateEvent {
+        StateEvent {
+            type_: type_.to_owned(),
+            state_key: state_key.to_owned(),
+            content,
+        }
+    }
+
+    fn is(&self, type_: &str, state_key: &str) -> bool {
+        self.type_ == type_ && self.state_key == state_key
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct CreateRoomReqBody {
+    visibility: Option<Visibility>,
+    room_alias_name: Option<String>,
+    name: Option<String>,
+    topic: Option<String>,
+    #[serde(default)]
+    invite: Vec<String>,
+    room_version: Option<String>,
+    creation_content: Option<Map<String, Value>>,
+    #[serde(default)]
+    initial_state: Vec<StateEvent>,
+    preset: Option<Preset>,
+    #[serde(default)]
+    is_direct: bool,
+    power_level_content_override: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct UpgradeRoomReqBody {
+    new_version: String,
+}
+
+fn generate_room_id(server_name: &str) -> String {
+    format!("!{}:{}", uuid::Uuid::new_v4().to_simple(), server_name)
+}
+
+/// Builds the state events which set up a new room, in the order they are sent.
+///
+/// `initial_state` takes precedence over the events implied by the preset, and is itself
+/// overridden by `name` and `topic`. `power_level_content_override` applies to both the default
+/// power levels and any given in `initial_state`.
+fn initial_state(
+    version: &RoomVersion,
+    creator: &UserId,
+    creator_membership: Value,
+    invitees: &[UserId],
+    body: CreateRoomReqBody,
+) -> Vec<StateEvent> {
+    let creator_id = creator.to_string();
+    let preset = body.preset.unwrap_or(match body.visibility {
+        Some(Visibility::Public) => Preset::Public,
+        _ => Preset::Private,
+    });
+
+    let mut create_content = body.creation_content.unwrap_or_default();
+    if !version.implicit_room_creator {
+        create_content.insert("creator".to_owned(), json!(creator_id));
+    }
+    create_content.insert("room_version".to_owned(), json!(version.id));
+
+    let mut users = Map::new();
+    users.insert(creator_id.clone(), json!(100));
+    if preset == Preset::TrustedPrivate {
+        for invitee in invitees {
+            users.insert(invitee.to_string(), json!(100));
+        }
+    }
+    let mut power_levels = json!({
+        "users": users,
+        "users_default": 0,
+        "events": {
+            "m.room.avatar": 50,
+            "m.room.canonical_alias": 50,
+            "m.room.encryption": 100,
+            "m.room.history_visibility": 100,
+            "m.room.name": 50,
+            "m.room.power_levels": 100,
+            "m.room.server_acl": 100,
+            "m.room.tombstone": 100,
+        },
+        "events_default": 0,
+        "state_default": 50,
+        "ban": 50,
+        "kick": 50,
+        "redact": 50,
+        "invite": if preset == Preset::Public { 50 } else { 0 },
+        "notifications": { "room": 50 },
+    });
+
+    // Power levels from `initial_state` are sent last, once the creator no longer needs the
+    // default ones to set up the room.
+    let mut initial_state = body.initial_state;
+    let mut initial_power_levels = initial_state
+        .iter()
+        .position(|event| event.is("m.room.power_levels", ""))
+        .map(|i| initial_state.remove(i));
+    if let Some(overrides) = body.power_level_content_override {
+        if let Value::Object(ref mut defaults) = power_levels {
+            defaults.extend(overrides.clone());
+        }
+        if let Some(Value::Object(ref mut initial)) = initial_power_levels
+            .as_mut()
+            .map(|event| &mut event.content)
+        {
+            initial.extend(overrides);
+        }
+    }
+
+    let mut events = vec![
+        StateEvent::new("m.room.create", "", Value::Object(create_content)),
+        StateEvent::new("m.room.member", &creator_id, creator_membership),
+        StateEvent::new("m.room.power_levels", "", power_levels),
+    ];
+
+    let (join_rule, guest_access) = match preset {
+        Preset::Public => ("public", "forbidden"),
+        Preset::Private | Preset::TrustedPrivate => ("invite", "can_join"),
+    };
+    let preset_events = vec![
+        StateEvent::new("m.room.join_rules", "", json!({ "join_rule": join_rule })),
+        StateEvent::new(
+            "m.room.history_visibility",
+            "",
+            json!({ "history_visibility": "shared" }),
+        ),
+        StateEvent::new(
+            "m.room.guest_access",
+            "",
+            json!({ "guest_access": guest_access }),
+        ),
+    ];
+    events.extend(preset_events.into_iter().filter(|preset_event| {
+        !initial_state
+            .iter()
+            .any(|event| event.is(&preset_event.type_, &preset_event.state_key))
+    }));
+
+    let name = body
+        .name
+        .map(|name| StateEvent::new("m.room.name", "", json!({ "name": name })));
+    let topic = body
+        .topic
+        .map(|topic| StateEvent::new("m.room.topic", "", json!({ "topic": topic })));
+    events.extend(initial_state.into_iter().filter(|event| {
+        !(name.is_some() && event.is("m.room.name", "")
+            || topic.is_some() && event.is("m.room.topic", ""))
+    }));
+    events.extend(name);
+    events.extend(topic);
+
+    for invitee in invitees {
+        let mut content = json!({ "membership": "invite" });
+        if body.is_direct {
+            content["is_direct"] = json!(true);
+        }
+        events.push(StateEvent::new(
+            "m.room.member",
+            &invitee.to_string(),
+            content,
+        ));
+    }
+    events.extend(initial_power_levels);
+
+    events
+}
+
+/// Sends the state events which set up a new room, in order.
+fn send_initial_state(
+    db: Client,
+    version: &'static RoomVersion,
+    room_id: String,
+    sender: String,
+    events: Vec<StateEvent>,
+) -> TransactionFutureBox<()> {
+    Box::new(
+        stream::iter_ok(events)
+            .fold(db, move |db, event| {
+                event_storage::append_event(
+                    db,
+                    version,
+                    room_id.clone(),
+                    sender.clone(),
+                    event.type_,
+                    Some(event.state_key),
+                    event.content,
+                )
+                .map(|(_, db)| db)
+            })
+            .map(|db| ((), db)),
+    )
+}
+
+/// Creates a room with the calling user as its only member, inviting any users requested.
+pub fn create_room(server: &LMServer, auth: AuthContext, req: Request<Body>) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let server_name = server.server_name.clone();
+
+    Box::new(parse_json_body(req.into_body()).and_then(
+        move |body: CreateRoomReqBody| -> EndpointFutureBox {
+            if body.room_alias_name.is_some() {
+                return Box::new(future::err(ROOM_ALIASES_UNSUPPORTED.into()));
+            }
+            let version = body
+                .room_version
+                .as_deref()
+                .unwrap_or(room_versions::DEFAULT_ROOM_VERSION);
+            let version = match room_versions::get(version) {
+                Some(version) => version,
+                None => return Box::new(future::err(UNSUPPORTED_ROOM_VERSION.into())),
+            };
+            if body
+                .initial_state
+                .iter()
+                .any(|event| !event.content.is_object())
+            {
+                return Box::new(future::err(ErrorBody::BAD_JSON.into()));
+            }
+            if body
+                .initial_state
+                .iter()
+                .any(|event| event.type_ == "m.room.create" || event.type_ == "m.room.member")
+            {
+                return Box::new(future::err(INVALID_INITIAL_STATE.into()));
+            }
+
+            let creator = UserId::new(&auth.localpart, &server_name);
+            let mut invitees: Vec<UserId> = Vec::new();
+            for invitee in &body.invite {
+                match UserId::parse(invitee) {
+                    Ok(ref invitee) if *invitee == creator || invitees.contains(invitee) => {}
+                    Ok(invitee) => invitees.push(invitee),
+                    Err(err) => return Box::new(future::err(err.into())),
+                }
+            }
+
+            let room_id = generate_room_id(&server_name);
+            let is_public = body.visibility == Some(Visibility::Public);
+            let user_id = auth.user_id;
+
+            Box::new(
+                db_pool
+                    .run(move |db| {
+                        db::lift(db::query_opt(db, PROFILE_QUERY, params![user_id])).and_then(
+                            move |(profile, db)| {
+                                let membership = join_content(profile);
+                                let events =
+                                    initial_state(version, &creator, membership, &invitees, body);
+                                let sender = creator.to_string();
+
+                                db::transaction(db, move |db| {
+                                    db::lift(db::execute(
+                                        db,
+                                        NEW_ROOM_QUERY,
+                                        params![room_id.clone(), version.id, user_id, is_public],
+                                    ))
+                                    .and_then({
+                                        let room_id = room_id.clone();
+                                        move |(_, db)| {
+                                            send_initial_state(db, version, room_id, sender, events)
+                                        }
+                                    })
+                                    .map(move |(_, db)| (room_id, db))
+                                })
+                            },
+                        )
+                    })
+                    .map_err(crate::Error::from)
+                    .map(|room_id| json_response(json!({ "room_id": room_id }))),
+            )
+        },
+    ))
+}
+
+/// Builds the state events which set up the replacement of a room, in the order they are sent.
+///
+/// The creator is raised to the power level needed to copy the state over, after which the
+/// original power levels are restored.
+fn upgraded_state(
+    version: &RoomVersion,
+    creator: &UserId,
+    creator_membership: Value,
+    old_state: &StateMap,
+    tombstone: &Event,
+) -> Vec<StateEvent> {
+    let creator_id = creator.to_string();
+
+    let mut create_content = events::get_state(old_state, "m.room.create", "")
+        .and_then(|create| create.content.as_object().cloned())
+        .unwrap_or_default();
+    create_content.remove("creator");
+    if !version.implicit_room_creator {
+        create_content.insert("creator".to_owned(), json!(creator_id));
+    }
+    create_content.insert("room_version".to_owned(), json!(version.id));
+    create_content.insert(
+        "predecessor".to_owned(),
+        json!({ "room_id": tombstone.room_id, "event_id": tombstone.event_id }),
+    );
+
+    let power_levels = match events::get_state(old_state, "m.room.power_levels", "") {
+        Some(event) => power_levels::integer_content(&event.content),
+        None => json!({ "users": { creator_id.clone(): 100 } }),
+    };
+    let levels = PowerLevels::from_content(&power_levels);
+    let needed_level = levels.events.values().fold(
+        cmp::max(levels.state_default, levels.ban),
+        |needed, &level| cmp::max(needed, level),
+    );
+    let creator_level = cmp::max(levels.user_level(&creator_id), needed_level);
+    let mut initial_power_levels = power_levels.clone();
+    initial_power_levels["users"][&creator_id] = json!(creator_level);
+
+    let mut events = vec![
+        StateEvent::new("m.room.create", "", Value::Object(create_content)),
+        StateEvent::new("m.room.member", &creator_id, creator_membership),
+        StateEvent::new("m.room.power_levels", "", initial_power_levels.clone()),
+    ];
+
+    for type_ in UPGRADED_STATE_TYPES {
+        if let Some(event) = events::get_state(old_state, type_, "") {
+            events.push(StateEvent::new(type_, "", event.content.clone()));
+        }
+    }
+    if let Some(event) = events::get_state(old_state, "m.room.aliases", creator.server_name()) {
+        events.push(StateEvent::new(
+            "m.room.aliases",
+            creator.server_name(),
+            event.content.clone(),
+        ));
+    }
+
+    let mut bans: Vec<_> = old_state
+        .values()
+        .filter(|event| event.membership() == Some("ban"))
+        .filter_map(|event| event.state_key.as_ref().map(|user_id| (user_id, event)))
+        .filter(|(user_id, _)| levels.user_level(user_id) < creator_level)
+        .collect();
+    bans.sort_by_key(|(user_id, _)| user_id.as_str());
+    for (user_id, event) in bans {
+        let mut content = json!({ "membership": "ban" });
+        if let Some(reason) = event.content.get("reason") {
+            content["reason"] = reason.clone();
+        }
+        events.push(StateEvent::new("m.room.member", user_id, content));
+    }
+
+    if initial_power_levels != power_levels {
+        events.push(StateEvent::new("m.room.power_levels", "", power_levels));
+    }
+
+    events
+}
+
+/// The power levels which stop ordinary members of a replaced room from talking or inviting
+/// others, if `sender` may set them and they are not already that strict.
+fn restricted_power_levels(state: &StateMap, sender: &str) -> Option<Value> {
+    let event = events::get_state(state, "m.room.power_levels", "")?;
+    let levels = PowerLevels::from_content(&event.content);
+    let restricted_level = cmp::max(50, levels.users_default + 1);
+
+    let sender_level = levels.user_level(sender);
+    if sender_level < levels.event_level("m.room.power_levels", true)
+        || sender_level < restricted_level
+        || levels.events_default >= restricted_level && levels.invite >= restricted_level
+    {
+        return None;
+    }
+
+    let mut content = event.content.clone();
+    content["events_default"] = json!(cmp::max(levels.events_default, restricted_level));
+    content["invite"] = json!(cmp::max(levels.invite, restricted_level));
+    Some(content)
+}
+
+/// Replaces a room with a new one of another version, copying its state over.
+///
+/// The old room is tombstoned, pointing to its replacement, and its members lose the ability to
+/// send messages. The replacement takes its place in the room directory.
+pub fn upgrade_room(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let creator = UserId::new(&auth.localpart, &server.server_name);
+    let new_room_id = generate_room_id(&server.server_name);
+    let user_id = auth.user_id;
+
+    Box::new(parse_json_body(req.into_body()).and_then(
+        move |body: UpgradeRoomReqBody| -> EndpointFutureBox {
+            let version = match room_versions::get(&body.new_version) {
+                Some(version) => version,
+                None => return Box::new(future::err(UNSUPPORTED_ROOM_VERSION.into())),
+            };
+
+            Box::new(
+                db_pool
+                    .run(move |db| {
+                        db::lift(db::query_opt(db, PROFILE_QUERY, params![user_id])).and_then(
+                            move |(profile, db)| {
+                                let membership = join_content(profile);
+                                let sender = creator.to_string();
+
+                                db::transaction(db, move |db| {
+                                    room_participation::send_event(
+                                        db,
+                                        room_id.clone(),
+                                        sender.clone(),
+                                        "m.room.tombstone".to_owned(),
+                                        Some(String::new()),
+                                        json!({
+                                            "body": "This room has been replaced",
+                                            "replacement_room": new_room_id,
+                                        }),
+                                    )
+                                    .and_then({
+                                        let room_id = room_id.clone();
+                                        move |(tombstone, db)| {
+                                            event_storage::get_current_state(db, room_id.clone())
+                                                .and_then(move |(state, db)| {
+                                                    db::lift(db::query_opt(
+                                                        db,
+                                                        UNPUBLISH_ROOM_QUERY,
+                                                        params![room_id],
+                                                    ))
+                                                    .map(move |(row, db)| {
+                                                        let is_public =
+                                                            row.is_some_and(|row| row.get(0));
+                                                        ((tombstone, state, is_public), db)
+                                                    })
+                                                })
+                                        }
+                                    })
+                                    .and_then(
+                                        move |((tombstone, state, is_public), db)| {
+                                            let state = events::state_map(state);
+                                            let events = upgraded_state(
+                                                version, &creator, membership, &state, &tombstone,
+                                            );
+                                            let restricted =
+                                                restricted_power_levels(&state, &sender);
+
+                                            db::lift(db::execute(
+                                                db,
+                                                NEW_ROOM_QUERY,
+                                                params![
+                                                    new_room_id.clone(),
+                                                    version.id,
+                                                    user_id,
+                                                    is_public
+                                                ],
+                                            ))
+                                            .and_then({
+                                                let new_room_id = new_room_id.clone();
+                                                let sender = sender.clone();
+                                                move |(_, db)| {
+                                                    send_initial_state(
+                                                        db,
+                                                        version,
+                                                        new_room_id,
+                                                        sender,
+                                                        events,
+                                                    )
+                                                }
+                                            })
+                                            .and_then(move |(_, db)| -> TransactionFutureBox<()> {
+                                                match restricted {
+                                                    Some(content) => Box::new(
+                                                        room_participation::send_event(
+                                                            db,
+                                                            room_id,
+                                                            sender,
+                                                            "m.room.power_levels".to_owned(),
+                                                            Some(String::new()),
+                                                            content,
+                                                        )
+                                                        .map(|(_, db)| ((), db)),
+                                                    ),
+                                                    None => Box::new(future::ok(((), db))),
+                                                }
+                                            })
+                                            .map(move |(_, db)| (new_room_id, db))
+                                        },
+                                    )
+                                })
+                            },
+                        )
+                    })
+                    .map_err(crate::Error::from)
+                    .map(|room_id| json_response(json!({ "replacement_room": room_id }))),
+            )
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        initial_state, restricted_power_levels, upgraded_state, CreateRoomReqBody, Preset,
+        StateEvent, Visibility,
+    };
+    use crate::event_auth::{self, AuthError};
+    use crate::events::{self, Event, StateMap};
+    use crate::room_versions::{self, RoomVersion};
+    use crate::user_id::UserId;
+    use serde_json::{json, Value};
+
+    const ALICE: &str = "@alice:example.com";
+    const BOB: &str = "@bob:example.com";
+    const CAROL: &str = "@carol:example.com";
+
+    fn keys(events: &[StateEvent]) -> Vec<(&str, &str)> {
+        events
+            .iter()
+            .map(|event| (event.type_.as_str(), event.state_key.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn public_chat_defaults() {
+        let creator = UserId::new("alice", "example.com");
+        let body = CreateRoomReqBody {
+            visibility: Some(Visibility::Public),
+            topic: Some("Birds".to_owned()),
+            ..Default::default()
+        };
+        let version = room_versions::get("10").unwrap();
+        let events = initial_state(
+            version,
+            &creator,
+            json!({ "membership": "join" }),
+            &[],
+            body,
+        );
+
+        assert_eq!(
+            keys(&events),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.member", "@alice:example.com"),
+                ("m.room.power_levels", ""),
+                ("m.room.join_rules", ""),
+                ("m.room.history_visibility", ""),
+                ("m.room.guest_access", ""),
+                ("m.room.topic", ""),
+            ]
+        );
+        assert_eq!(events[0].content["creator"], "@alice:example.com");
+        assert_eq!(events[0].content["room_version"], "10");
+        assert_eq!(events[2].content["users"]["@alice:example.com"], 100);
+        assert_eq!(events[2].content["invite"], 50);
+        assert_eq!(events[3].content["join_rule"], "public");
+    }
+
+    #[test]
+    fn initial_state_precedence() {
+        let creator = UserId::new("alice", "example.com");
+        let invitee = UserId::new("bob", "example.com");
+        let body = CreateRoomReqBody {
+            name: Some("Waders".to_owned()),
+            preset: Some(Preset::TrustedPrivate),
+            is_direct: true,
+            initial_state: vec![
+                StateEvent::new("m.room.join_rules", "", json!({ "join_rule": "public" })),
+                StateEvent::new("m.room.name", "", json!({ "name": "Ignored" })),
+                StateEvent::new("m.room.power_levels", "", json!({ "ban": 100 })),
+            ],
+            power_level_content_override: Some(json!({ "kick": 75 }).as_object().unwrap().clone()),
+            ..Default::default()
+        };
+        let version = room_versions::get("11").unwrap();
+        let events = initial_state(
+            version,
+            &creator,
+            json!({ "membership": "join" }),
+            &[invitee],
+            body,
+        );
+
+        assert_eq!(
+            keys(&events),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.member", "@alice:example.com"),
+                ("m.room.power_levels", ""),
+                ("m.room.history_visibility", ""),
+                ("m.room.guest_access", ""),
+                ("m.room.join_rules", ""),
+                ("m.room.name", ""),
+                ("m.room.member", "@bob:example.com"),
+                ("m.room.power_levels", ""),
+            ]
+        );
+        assert_eq!(events[0].content, json!({ "room_version": "11" }));
+        assert_eq!(events[2].content["users"]["@bob:example.com"], 100);
+        assert_eq!(events[2].content["kick"], 75);
+        assert_eq!(events[8].content, json!({ "ban": 100, "kick": 75 }));
+        assert_eq!(events[5].content["join_rule"], "public");
+        assert_eq!(events[6].content["name"], "Waders");
+        assert_eq!(
+            events[7].content,
+            json!({ "membership": "invite", "is_direct": true })
+        );
+    }
+
+    /// Runs the events which set up a room through the authorization rules, in order.
+    fn check_initial_state(
+        version: &RoomVersion,
+        creator: &str,
+        events: Vec<StateEvent>,
+    ) -> Result<(), AuthError> {
+        let mut state = StateMap::new();
+        let mut prev_events = Vec::new();
+        for (i, initial) in events.into_iter().enumerate() {
+            let mut event = event(&initial.type_, &initial.state_key, creator, initial.content);
+            event.event_id = format!("${}", i);
+            event.prev_events = prev_events;
+            prev_events = vec![event.event_id.clone()];
+            event_auth::check(version, &event, &state)?;
+            state.insert((event.type_.clone(), initial.state_key), event);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn initial_state_is_authorized() {
+        let creator = UserId::new("alice", "example.com");
+        let invitee = UserId::new("bob", "example.com");
+        let version = room_versions::get("10").unwrap();
+        let body = CreateRoomReqBody {
+            name: Some("Waders".to_owned()),
+            topic: Some("Avocets".to_owned()),
+            preset: Some(Preset::Private),
+            initial_state: vec![StateEvent::new(
+                "m.room.power_levels",
+                "",
+                json!({ "ban": 100, "invite": 100, "state_default": 100 }),
+            )],
+            power_level_content_override: Some(json!({ "kick": 75 }).as_object().unwrap().clone()),
+            ..Default::default()
+        };
+        let events = initial_state(
+            version,
+            &creator,
+            json!({ "membership": "join" }),
+            &[invitee],
+            body,
+        );
+
+        assert_eq!(check_initial_state(version, ALICE, events), Ok(()));
+    }
+
+    fn event(type_: &str, state_key: &str, sender: &str, content: Value) -> Event {
+        Event {
+            event_id: format!("${}", type_),
+            room_id: "!old:example.com".to_owned(),
+            type_: type_.to_owned(),
+            state_key: Some(state_key.to_owned()),
+            sender: sender.to_owned(),
+            origin_server_ts: 0,
+            content,
+            redacts: None,
+            depth: 1,
+            prev_events: Vec::new(),
+            auth_events: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn upgraded_room_state() {
+        let creator = UserId::new("bob", "example.com");
+        let old_state = events::state_map(vec![
+            event(
+                "m.room.create",
+                "",
+                ALICE,
+                json!({ "creator": ALICE, "room_version": "2", "m.federate": false }),
+            ),
+            event(
+                "m.room.power_levels",
+                "",
+                ALICE,
+                json!({
+                    "users": { ALICE: 100, BOB: "50" },
+                    "events": { "m.room.tombstone": 75 },
+                }),
+            ),
+            event(
+                "m.room.join_rules",
+                "",
+                ALICE,
+                json!({ "join_rule": "invite" }),
+            ),
+            event("m.room.topic", "", ALICE, json!({ "topic": "Owls" })),
+            event("m.room.pinned_events", "", ALICE, json!({ "pinned": [] })),
+            event(
+                "m.room.aliases",
+                "example.com",
+                ALICE,
+                json!({ "aliases": ["#a:example.com"] }),
+            ),
+            event(
+                "m.room.aliases",
+                "example.org",
+                ALICE,
+                json!({ "aliases": ["#a:example.org"] }),
+            ),
+            event(
+                "m.room.member",
+                ALICE,
+                ALICE,
+                json!({ "membership": "join" }),
+            ),
+            event(
+                "m.room.member",
+                CAROL,
+                ALICE,
+                json!({ "membership": "ban", "reason": "Spam", "displayname": "Carol" }),
+            ),
+        ]);
+        let mut tombstone = event("m.room.tombstone", "", BOB, json!({}));
+        tombstone.event_id = "$tombstone".to_owned();
+
+        let version = room_versions::get("11").unwrap();
+        let events = upgraded_state(
+            version,
+            &creator,
+            json!({ "membership": "join" }),
+            &old_state,
+            &tombstone,
+        );
+
+        assert_eq!(
+            keys(&events),
+            vec![
+                ("m.room.create", ""),
+                ("m.room.member", BOB),
+                ("m.room.power_levels", ""),
+                ("m.room.join_rules", ""),
+                ("m.room.topic", ""),
+                ("m.room.aliases", "example.com"),
+                ("m.room.member", CAROL),
+                ("m.room.power_levels", ""),
+            ]
+        );
+        assert_eq!(
+            events[0].content,
+            json!({
+                "m.federate": false,
+                "room_version": "11",
+                "predecessor": { "room_id": "!old:example.com", "event_id": "$tombstone" },
+            })
+        );
+        assert_eq!(events[2].content["users"], json!({ ALICE: 100, BOB: 75 }));
+        assert_eq!(
+            events[6].content,
+            json!({ "membership": "ban", "reason": "Spam" })
+        );
+        assert_eq!(events[7].content["users"], json!({ ALICE: 100, BOB: 50 }));
+    }
+
+    #[test]
+    fn restricted_levels() {
+        let state = events::state_map(vec![event(
+            "m.room.power_levels",
+            "",
+            ALICE,
+            json!({ "users": { ALICE: 100, BOB: 50 }, "invite": 0 }),
+        )]);
+
+        assert_eq!(
+            restricted_power_levels(&state, ALICE),
+            Some(json!({ "users": { ALICE: 100, BOB: 50 }, "invite": 50, "events_default": 50 }))
+        );
+        assert_eq!(restricted_power_levels(&state, CAROL), None);
+        assert_eq!(restricted_power_levels(&Default::default(), ALICE), None);
+
+        let restricted = events::state_map(vec![event(
+            "m.room.power_levels",
+            "",
+            ALICE,
+            json!({ "users": { ALICE: 100 }, "invite": 50, "events_default": 60 }),
+        )]);
+        assert_eq!(restricted_power_levels(&restricted, ALICE), None);
+    }
+}
@@ -0,0 +1,459 @@
+use futures::{future, Future};
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio_postgres::{Client, Row};
+
+use crate::authentication::AuthContext;
+use crate::db::{self, TransactionFutureBox};
+use crate::event_storage;
+use crate::events::Event;
+use crate::room_participation;
+use crate::user_id::UserId;
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+pub const PROFILE_QUERY: &str = "SELECT displayname, avatar_url FROM profiles WHERE user_id=$1";
+
+const MEMBERSHIP_QUERY: &str = "SELECT membership, event_id FROM current_state \
+                                WHERE room_id=$1 AND type='m.room.member' AND state_key=$2";
+
+const FORGET_ROOM_QUERY: &str = "INSERT INTO forgotten_rooms (user_id, room_id, event_id) \
+     VALUES ($1, $2, $3) \
+     ON CONFLICT (user_id, room_id) DO UPDATE SET event_id=EXCLUDED.event_id";
+
+// `$1` is the user's ID and `$2` their internal ID.
+const JOINED_ROOMS_QUERY: &str = "SELECT current_state.room_id, current_state.event_id, \
+     forgotten_rooms.event_id FROM current_state LEFT JOIN forgotten_rooms \
+     ON forgotten_rooms.room_id = current_state.room_id AND forgotten_rooms.user_id = $2 \
+     WHERE current_state.type='m.room.member' AND current_state.state_key=$1 \
+     AND current_state.membership='join'";
+
+const NOT_KICKABLE: ErrorBody = ErrorBody::new_static(
+    error_code::M_FORBIDDEN,
+    "The user is not joined, invited or knocking",
+);
+const NOT_BANNED: ErrorBody =
+    ErrorBody::new_static(error_code::M_FORBIDDEN, "The user is not banned");
+// No aliases can be created yet, so none of them can be found either.
+const ALIAS_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "Unknown room alias");
+const STILL_IN_ROOM: ErrorBody = ErrorBody::new_static(
+    error_code::M_UNKNOWN,
+    "You must leave the room before forgetting it",
+);
+
+#[derive(Deserialize)]
+struct ReasonReqBody {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TargetReqBody {
+    user_id: String,
+    reason: Option<String>,
+}
+
+/// The content of a user's join event, carrying over their profile.
+pub fn join_content(profile: Option<Row>) -> Value {
+    match profile {
+        Some(profile) => profile_join_content(profile.get(0), profile.get(1)),
+        None => profile_join_content(None, None),
+    }
+}
+
+fn profile_join_content(displayname: Option<String>, avatar_url: Option<String>) -> Value {
+    let mut content = json!({ "membership": "join" });
+    if let Some(displayname) = displayname {
+        content["displayname"] = json!(displayname);
+    }
+    if let Some(avatar_url) = avatar_url {
+        content["avatar_url"] = json!(avatar_url);
+    }
+
+    content
+}
+
+fn with_reason(mut content: Value, reason: Option<String>) -> Value {
+    if let Some(reason) = reason {
+        content["reason"] = json!(reason);
+    }
+
+    content
+}
+
+/// Kicks only remove users from the room or withdraw their invites and knocks, so that they do not
+/// lift bans.
+fn check_kick(membership: Option<&str>) -> Result<(), ErrorBody> {
+    match membership {
+        Some("join") | Some("invite") | Some("knock") => Ok(()),
+        _ => Err(NOT_KICKABLE),
+    }
+}
+
+/// Only banned users can be unbanned.
+fn check_unban(membership: Option<&str>) -> Result<(), ErrorBody> {
+    if membership == Some("ban") {
+        Ok(())
+    } else {
+        Err(NOT_BANNED)
+    }
+}
+
+/// Rooms can be forgotten once the user has left or been banned from them, or if they were never
+/// in them at all.
+fn check_forget(membership: Option<&str>) -> Result<(), ErrorBody> {
+    match membership {
+        None | Some("leave") | Some("ban") => Ok(()),
+        Some(_) => Err(STILL_IN_ROOM),
+    }
+}
+
+/// Whether a room is forgotten, given the user's current membership event in it.
+///
+/// Forgetting a room applies to the membership event it was forgotten at, so the room comes
+/// back as soon as the user is invited to it or joins it again.
+fn is_forgotten(membership_event_id: &str, forgotten_event_id: Option<&str>) -> bool {
+    forgotten_event_id == Some(membership_event_id)
+}
+
+/// Sends an `m.room.member` event for `target`, provided that `check` accepts its current
+/// membership.
+fn update_membership<F>(
+    db: Client,
+    room_id: String,
+    sender: String,
+    target: String,
+    content: Value,
+    check: F,
+) -> TransactionFutureBox<Event>
+where
+    F: FnOnce(Option<&str>) -> Result<(), ErrorBody> + Send + 'static,
+{
+    Box::new(event_storage::lock_room(db, room_id.clone()).and_then(
+        move |(version, db)| -> TransactionFutureBox<Event> {
+            let version = match version {
+                Some(version) => version,
+                None => {
+                    return Box::new(future::err((room_participation::ROOM_NOT_FOUND.into(), db)))
+                }
+            };
+
+            Box::new(
+                db::lift(db::query_opt(
+                    db,
+                    MEMBERSHIP_QUERY,
+                    params![room_id.clone(), target.clone()],
+                ))
+                .and_then(move |(row, db)| -> TransactionFutureBox<Event> {
+                    let membership = row.and_then(|row| row.get::<_, Option<String>>(0));
+                    if let Err(err) = check(membership.as_deref()) {
+                        return Box::new(future::err((err.into(), db)));
+                    }
+
+                    event_storage::append_event(
+                        db,
+                        version,
+                        room_id,
+                        sender,
+                        "m.room.member".to_owned(),
+                        Some(target),
+                        content,
+                    )
+                }),
+            )
+        },
+    ))
+}
+
+/// Sets the membership of the user named in the request body.
+fn update_target_membership<F>(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+    membership: &'static str,
+    check: F,
+) -> EndpointFutureBox
+where
+    F: FnOnce(Option<&str>) -> Result<(), ErrorBody> + Send + 'static,
+{
+    let db_pool = server.db_pool.clone();
+    let sender = UserId::new(&auth.localpart, &server.server_name).to_string();
+
+    Box::new(parse_json_body(req.into_body()).and_then(
+        move |body: TargetReqBody| -> EndpointFutureBox {
+            let target = match UserId::parse(&body.user_id) {
+                Ok(target) => target.to_string(),
+                Err(err) => return Box::new(future::err(err.into())),
+            };
+            let content = with_reason(json!({ "membership": membership }), body.reason);
+
+            Box::new(
+                db_pool
+                    .run(move |db| {
+                        db::transaction(db, move |db| {
+                            update_membership(db, room_id, sender, target, content, check)
+                        })
+                    })
+                    .map_err(crate::Error::from)
+                    .map(|_| json_response(json!({}))),
+            )
+        },
+    ))
+}
+
+/// Joins the calling user to a room by its ID.
+pub fn join(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let sender = UserId::new(&auth.localpart, &server.server_name).to_string();
+    let user_id = auth.user_id;
+
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |body: ReasonReqBody| {
+                db_pool
+                    .run(move |db| {
+                        db::lift(db::query_opt(db, PROFILE_QUERY, params![user_id])).and_then(
+                            move |(profile, db)| {
+                                let content = with_reason(join_content(profile), body.reason);
+                                db::transaction(db, move |db| {
+                                    update_membership(
+                                        db,
+                                        room_id,
+                                        sender.clone(),
+                                        sender,
+                                        content,
+                                        |_| Ok(()),
+                                    )
+                                })
+                            },
+                        )
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|event| json_response(json!({ "room_id": event.room_id }))),
+    )
+}
+
+/// Joins the calling user to a room by its ID or one of its aliases, though aliases are not
+/// resolved yet.
+pub fn join_by_id_or_alias(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id_or_alias: String,
+) -> EndpointFutureBox {
+    if room_id_or_alias.starts_with('#') {
+        return Box::new(future::err(ALIAS_NOT_FOUND.into()));
+    }
+
+    join(server, auth, req, room_id_or_alias)
+}
+
+/// Makes the calling user leave a room, or rejects their invite to it.
+pub fn leave(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let sender = UserId::new(&auth.localpart, &server.server_name).to_string();
+
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |body: ReasonReqBody| {
+                let content = with_reason(json!({ "membership": "leave" }), body.reason);
+                db_pool
+                    .run(move |db| {
+                        db::transaction(db, move |db| {
+                            update_membership(db, room_id, sender.clone(), sender, content, |_| {
+                                Ok(())
+                            })
+                        })
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+pub fn invite(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    update_target_membership(server, auth, req, room_id, "invite", |_| Ok(()))
+}
+
+pub fn kick(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    update_target_membership(server, auth, req, room_id, "leave", check_kick)
+}
+
+pub fn ban(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    update_target_membership(server, auth, req, room_id, "ban", |_| Ok(()))
+}
+
+/// Lifts a ban, which leaves the user free to join the room again if its join rules allow.
+pub fn unban(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    update_target_membership(server, auth, req, room_id, "leave", check_unban)
+}
+
+/// Forgets a room the calling user is no longer in, so that it is left out of their rooms.
+pub fn forget(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    let mxid = UserId::new(&auth.localpart, &server.server_name).to_string();
+    let user_id = auth.user_id;
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| {
+                db::lift(db::query_opt(
+                    db,
+                    MEMBERSHIP_QUERY,
+                    params![room_id.clone(), mxid],
+                ))
+                .and_then(move |(row, db)| -> TransactionFutureBox<()> {
+                    let row = match row {
+                        Some(row) => row,
+                        None => return Box::new(future::ok(((), db))),
+                    };
+                    if let Err(err) = check_forget(row.get::<_, Option<String>>(0).as_deref()) {
+                        return Box::new(future::err((err.into(), db)));
+                    }
+
+                    let event_id: String = row.get(1);
+                    Box::new(
+                        db::lift(db::execute(
+                            db,
+                            FORGET_ROOM_QUERY,
+                            params![user_id, room_id, event_id],
+                        ))
+                        .map(|(_, db)| ((), db)),
+                    )
+                })
+            })
+            .map_err(crate::Error::from)
+            .map(|()| json_response(json!({}))),
+    )
+}
+
+/// Lists the rooms the calling user is joined to.
+pub fn joined_rooms(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+) -> EndpointFutureBox {
+    let mxid = UserId::new(&auth.localpart, &server.server_name).to_string();
+    let user_id = auth.user_id;
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::query(db, JOINED_ROOMS_QUERY, params![mxid, user_id]))
+            .map_err(crate::Error::from)
+            .map(|rows| {
+                let rooms: Vec<String> = rows
+                    .iter()
+                    .filter(|row| !is_forgotten(row.get(1), row.get(2)))
+                    .map(|row| row.get(0))
+                    .collect();
+                json_response(json!({ "joined_rooms": rooms }))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_forget, check_kick, check_unban, is_forgotten, profile_join_content, with_reason,
+    };
+    use crate::error_code;
+    use serde_json::json;
+
+    #[test]
+    fn join_contents() {
+        assert_eq!(
+            profile_join_content(None, None),
+            json!({ "membership": "join" })
+        );
+        assert_eq!(
+            profile_join_content(Some("Alice".to_owned()), Some("mxc://x/a".to_owned())),
+            json!({ "membership": "join", "displayname": "Alice", "avatar_url": "mxc://x/a" })
+        );
+    }
+
+    #[test]
+    fn reasons() {
+        let content = json!({ "membership": "ban" });
+        assert_eq!(with_reason(content.clone(), None), content);
+        assert_eq!(
+            with_reason(content, Some("Spam".to_owned())),
+            json!({ "membership": "ban", "reason": "Spam" })
+        );
+    }
+
+    #[test]
+    fn kicks() {
+        for membership in &["join", "invite", "knock"] {
+            assert!(check_kick(Some(membership)).is_ok());
+        }
+        for membership in &[None, Some("leave"), Some("ban")] {
+            let err = check_kick(*membership).unwrap_err();
+            assert_eq!(err.errcode, error_code::M_FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn unbans() {
+        assert!(check_unban(Some("ban")).is_ok());
+        for membership in &[None, Some("join"), Some("leave"), Some("invite")] {
+            let err = check_unban(*membership).unwrap_err();
+            assert_eq!(err.errcode, error_code::M_FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn forgetting() {
+        assert!(check_forget(None).is_ok());
+        assert!(check_forget(Some("leave")).is_ok());
+        assert!(check_forget(Some("ban")).is_ok());
+        for membership in &[Some("join"), Some("invite"), Some("knock")] {
+            let err = check_forget(*membership).unwrap_err();
+            assert_eq!(err.errcode, error_code::M_UNKNOWN);
+        }
+    }
+
+    #[test]
+    fn forgotten_rooms() {
+        assert!(is_forgotten("$leave:localhost", Some("$leave:localhost")));
+        assert!(!is_forgotten("$rejoin:localhost", Some("$leave:localhost")));
+        assert!(!is_forgotten("$join:localhost", None));
+    }
+}
@@ -0,0 +1,230 @@
+use futures::{future, Future};
+use hyper::{Body, Request};
+use serde_json::{json, Map, Value};
+use tokio_postgres::Client;
+
+use crate::authentication::AuthContext;
+use crate::db::{self, TransactionFutureBox};
+use crate::event_storage;
+use crate::events::{self, Event, StateMap};
+use crate::user_id::UserId;
+use crate::{error_code, json_response, parse_json_body, EndpointFutureBox, ErrorBody, LMServer};
+
+const EVENT_TRANSACTION_QUERY: &str =
+    "SELECT event_id FROM event_transactions WHERE token_id=$1 AND txn_id=$2";
+
+const NEW_EVENT_TRANSACTION_QUERY: &str =
+    "INSERT INTO event_transactions (token_id, txn_id, event_id) VALUES ($1, $2, $3)";
+
+pub const ROOM_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "Unknown room");
+const NOT_IN_ROOM: ErrorBody =
+    ErrorBody::new_static(error_code::M_FORBIDDEN, "You are not joined to this room");
+const STATE_NOT_FOUND: ErrorBody =
+    ErrorBody::new_static(error_code::M_NOT_FOUND, "State event not found");
+
+/// Loads the current state of a room, which `user_id` must be joined to.
+fn joined_room_state(
+    db: Client,
+    room_id: String,
+    user_id: String,
+) -> TransactionFutureBox<StateMap> {
+    Box::new(
+        event_storage::get_current_state(db, room_id).and_then(move |(state, db)| {
+            let state = events::state_map(state);
+            match events::get_state(&state, "m.room.member", &user_id).and_then(Event::membership) {
+                Some("join") => Ok((state, db)),
+                _ => Err((NOT_IN_ROOM.into(), db)),
+            }
+        }),
+    )
+}
+
+/// Appends an event to an existing room, which must be locked for the rest of the transaction.
+pub fn send_event(
+    db: Client,
+    room_id: String,
+    sender: String,
+    type_: String,
+    state_key: Option<String>,
+    content: Value,
+) -> TransactionFutureBox<Event> {
+    Box::new(event_storage::lock_room(db, room_id.clone()).and_then(
+        move |(version, db)| -> TransactionFutureBox<Event> {
+            let version = match version {
+                Some(version) => version,
+                None => return Box::new(future::err((ROOM_NOT_FOUND.into(), db))),
+            };
+            event_storage::append_event(db, version, room_id, sender, type_, state_key, content)
+        },
+    ))
+}
+
+/// Sends a message event to a room the calling user is joined to.
+///
+/// Retrying a request with the same transaction ID and access token returns the event sent the
+/// first time instead of sending it again.
+pub fn send_message(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+    event_type: String,
+    txn_id: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let sender = UserId::new(&auth.localpart, &server.server_name).to_string();
+    let token_id = auth.token_id;
+
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |content: Map<String, Value>| {
+                db_pool
+                    .run(move |db| {
+                        db::transaction(db, move |db| {
+                            event_storage::lock_room(db, room_id.clone())
+                                .and_then({
+                                    let txn_id = txn_id.clone();
+                                    move |(version, db)| -> TransactionFutureBox<_> {
+                                        let version = match version {
+                                            Some(version) => version,
+                                            None => {
+                                                return Box::new(future::err((
+                                                    ROOM_NOT_FOUND.into(),
+                                                    db,
+                                                )))
+                                            }
+                                        };
+                                        Box::new(
+                                            db::lift(db::query_opt(
+                                                db,
+                                                EVENT_TRANSACTION_QUERY,
+                                                params![token_id, txn_id],
+                                            ))
+                                            .map(
+                                                move |(row, db)| {
+                                                    ((version, row.map(|row| row.get(0))), db)
+                                                },
+                                            ),
+                                        )
+                                    }
+                                })
+                                .and_then(
+                                    move |((version, sent), db)| -> TransactionFutureBox<String> {
+                                        if let Some(event_id) = sent {
+                                            return Box::new(future::ok((event_id, db)));
+                                        }
+
+                                        Box::new(
+                                            event_storage::append_event(
+                                                db,
+                                                version,
+                                                room_id,
+                                                sender,
+                                                event_type,
+                                                None,
+                                                Value::Object(content),
+                                            )
+                                            .and_then(
+                                                move |(event, db)| {
+                                                    db::lift(db::execute(
+                                                        db,
+                                                        NEW_EVENT_TRANSACTION_QUERY,
+                                                        params![
+                                                            token_id,
+                                                            txn_id,
+                                                            event.event_id.clone()
+                                                        ],
+                                                    ))
+                                                    .map(move |(_, db)| (event.event_id, db))
+                                                },
+                                            ),
+                                        )
+                                    },
+                                )
+                        })
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|event_id| json_response(json!({ "event_id": event_id }))),
+    )
+}
+
+/// Sends a state event to a room, replacing the current state with the same type and key.
+pub fn send_state_event(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+    room_id: String,
+    event_type: String,
+    state_key: String,
+) -> EndpointFutureBox {
+    let db_pool = server.db_pool.clone();
+    let sender = UserId::new(&auth.localpart, &server.server_name).to_string();
+
+    Box::new(
+        parse_json_body(req.into_body())
+            .and_then(move |content: Map<String, Value>| {
+                db_pool
+                    .run(move |db| {
+                        db::transaction(db, move |db| {
+                            send_event(
+                                db,
+                                room_id,
+                                sender,
+                                event_type,
+                                Some(state_key),
+                                Value::Object(content),
+                            )
+                        })
+                    })
+                    .map_err(crate::Error::from)
+            })
+            .map(|event| json_response(json!({ "event_id": event.event_id }))),
+    )
+}
+
+/// Returns the content of a single state event in the current state of a room.
+pub fn get_state_event(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    room_id: String,
+    event_type: String,
+    state_key: String,
+) -> EndpointFutureBox {
+    let user_id = UserId::new(&auth.localpart, &server.server_name).to_string();
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| joined_room_state(db, room_id, user_id))
+            .map_err(crate::Error::from)
+            .and_then(move |state| {
+                let event =
+                    events::get_state(&state, &event_type, &state_key).ok_or(STATE_NOT_FOUND)?;
+                Ok(json_response(event.content.clone()))
+            }),
+    )
+}
+
+/// Returns every event in the current state of a room.
+pub fn get_state(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+    room_id: String,
+) -> EndpointFutureBox {
+    let user_id = UserId::new(&auth.localpart, &server.server_name).to_string();
+
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| joined_room_state(db, room_id, user_id))
+            .map_err(crate::Error::from)
+            .map(|state| {
+                let events: Vec<_> = state.values().map(Event::to_client_json).collect();
+                json_response(Value::Array(events))
+            }),
+    )
+}
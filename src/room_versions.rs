@@ -0,0 +1,451 @@
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::events::{self, Event};
+
+/// The version of newly created rooms, unless another one is requested.
+pub const DEFAULT_ROOM_VERSION: &str = "10";
+
+/// How the IDs of events in a room are formed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventIdFormat {
+    /// `$opaque_id:server_name`, chosen by the server which created the event.
+    Arbitrary,
+    /// The reference hash of the event, in unpadded base64.
+    ReferenceHash,
+    /// The reference hash of the event, in unpadded URL-safe base64.
+    UrlSafeReferenceHash,
+}
+
+/// Which parts of an event are kept when it is redacted, named after the room version which
+/// introduced each set of rules.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum RedactionRules {
+    V1,
+    /// `m.room.aliases` events lose their aliases.
+    V6,
+    /// `m.room.join_rules` events keep `allow`.
+    V8,
+    /// `m.room.member` events keep `join_authorised_via_users_server`.
+    V9,
+    /// Create events keep all of their content, and a few more keys are kept elsewhere.
+    V11,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateResolution {
+    V1,
+    V2,
+}
+
+/// The algorithms and rules which make up a room version.
+#[derive(Debug, PartialEq)]
+pub struct RoomVersion {
+    pub id: &'static str,
+    pub stable: bool,
+    pub event_id_format: EventIdFormat,
+    pub redaction_rules: RedactionRules,
+    pub state_resolution: StateResolution,
+    /// Whether `m.room.aliases` events may only be sent by the server named by their state key.
+    pub special_case_aliases: bool,
+    /// Whether users may knock on rooms with the `knock` join rule.
+    pub knocking: bool,
+    /// Whether the `restricted` join rule lets members of other rooms join.
+    pub restricted_joins: bool,
+    /// Whether the `knock_restricted` join rule combines knocking with restricted joins.
+    pub knock_restricted_joins: bool,
+    /// Whether power levels must be integers, rather than also strings containing integers.
+    pub integer_power_levels: bool,
+    /// Whether the creator of a room is the sender of its create event, rather than the
+    /// `creator` in its content.
+    pub implicit_room_creator: bool,
+}
+
+const V1: RoomVersion = RoomVersion {
+    id: "1",
+    stable: true,
+    event_id_format: EventIdFormat::Arbitrary,
+    redaction_rules: RedactionRules::V1,
+    state_resolution: StateResolution::V1,
+    special_case_aliases: true,
+    knocking: false,
+    restricted_joins: false,
+    knock_restricted_joins: false,
+    integer_power_levels: false,
+    implicit_room_creator: false,
+};
+
+const V2: RoomVersion = RoomVersion {
+    id: "2",
+    state_resolution: StateResolution::V2,
+    ..V1
+};
+
+const V3: RoomVersion = RoomVersion {
+    id: "3",
+    event_id_format: EventIdFormat::ReferenceHash,
+    ..V2
+};
+
+const V4: RoomVersion = RoomVersion {
+    id: "4",
+    event_id_format: EventIdFormat::UrlSafeReferenceHash,
+    ..V3
+};
+
+const V5: RoomVersion = RoomVersion { id: "5", ..V4 };
+
+const V6: RoomVersion = RoomVersion {
+    id: "6",
+    redaction_rules: RedactionRules::V6,
+    special_case_aliases: false,
+    ..V5
+};
+
+const V7: RoomVersion = RoomVersion {
+    id: "7",
+    knocking: true,
+    ..V6
+};
+
+const V8: RoomVersion = RoomVersion {
+    id: "8",
+    redaction_rules: RedactionRules::V8,
+    restricted_joins: true,
+    ..V7
+};
+
+const V9: RoomVersion = RoomVersion {
+    id: "9",
+    redaction_rules: RedactionRules::V9,
+    ..V8
+};
+
+const V10: RoomVersion = RoomVersion {
+    id: "10",
+    knock_restricted_joins: true,
+    integer_power_levels: true,
+    ..V9
+};
+
+const V11: RoomVersion = RoomVersion {
+    id: "11",
+    redaction_rules: RedactionRules::V11,
+    implicit_room_creator: true,
+    ..V10
+};
+
+/// Every room version supported by the server.
+pub const ROOM_VERSIONS: &[RoomVersion] = &[V1, V2, V3, V4, V5, V6, V7, V8, V9, V10, V11];
+
+pub fn get(id: &str) -> Option<&'static RoomVersion> {
+    ROOM_VERSIONS.iter().find(|version| version.id == id)
+}
+
+fn sha256_base64(value: &Value, config: base64::Config) -> String {
+    base64::encode_config(
+        &Sha256::digest(events::canonical_json(value).as_bytes()),
+        config,
+    )
+}
+
+impl RoomVersion {
+    /// The user who created a room, given its create event.
+    pub fn creator<'a>(&self, create: &'a Event) -> Option<&'a str> {
+        if self.implicit_room_creator {
+            Some(&create.sender)
+        } else {
+            create.content["creator"].as_str()
+        }
+    }
+
+    /// Generates the ID of a new event, whose own `event_id` is ignored.
+    ///
+    /// Arbitrary IDs are namespaced by the server of the sender, which is the one creating it.
+    pub fn event_id(&self, event: &Event) -> String {
+        let config = match self.event_id_format {
+            EventIdFormat::Arbitrary => {
+                let server_name = event.sender.split_once(':').map_or("", |(_, name)| name);
+                return events::generate_event_id(server_name);
+            }
+            EventIdFormat::ReferenceHash => base64::STANDARD_NO_PAD,
+            EventIdFormat::UrlSafeReferenceHash => base64::URL_SAFE_NO_PAD,
+        };
+
+        let mut pdu = json!({
+            "room_id": event.room_id,
+            "type": event.type_,
+            "sender": event.sender,
+            "origin_server_ts": event.origin_server_ts,
+            "content": event.content,
+            "depth": event.depth,
+            "prev_events": event.prev_events,
+            "auth_events": event.auth_events,
+        });
+        if let Some(ref state_key) = event.state_key {
+            pdu["state_key"] = json!(state_key);
+        }
+        if let Some(ref redacts) = event.redacts {
+            pdu["redacts"] = json!(redacts);
+        }
+        // The content hash keeps events which differ only in redacted content apart.
+        pdu["hashes"] = json!({ "sha256": sha256_base64(&pdu, base64::STANDARD_NO_PAD) });
+
+        format!("${}", sha256_base64(&self.redact(&pdu), config))
+    }
+
+    /// The ID of the event redacted by a redaction, which moved into the content in version 11.
+    pub fn redacts<'a>(&self, event: &'a Event) -> Option<&'a str> {
+        if self.redaction_rules >= RedactionRules::V11 {
+            event.content["redacts"].as_str()
+        } else {
+            event.redacts.as_deref()
+        }
+    }
+
+    /// Strips an event, in its federation format, down to the keys which survive redaction.
+    pub fn redact(&self, event: &Value) -> Value {
+        let mut keys = vec![
+            "event_id",
+            "type",
+            "room_id",
+            "sender",
+            "state_key",
+            "content",
+            "hashes",
+            "signatures",
+            "depth",
+            "prev_events",
+            "auth_events",
+            "origin_server_ts",
+        ];
+        if self.redaction_rules < RedactionRules::V11 {
+            keys.extend(&["origin", "membership", "prev_state"]);
+        }
+
+        let mut redacted = keep_keys(event, &keys);
+        if let Some(content) = event.get("content") {
+            let type_ = event["type"].as_str().unwrap_or_default();
+            redacted.insert("content".to_owned(), self.redact_content(type_, content));
+        }
+
+        Value::Object(redacted)
+    }
+
+    fn redact_content(&self, type_: &str, content: &Value) -> Value {
+        let rules = self.redaction_rules;
+        let keys: &[&str] = match type_ {
+            "m.room.member" if rules >= RedactionRules::V9 => {
+                &["membership", "join_authorised_via_users_server"]
+            }
+            "m.room.member" => &["membership"],
+            "m.room.create" if rules >= RedactionRules::V11 => return content.clone(),
+            "m.room.create" => &["creator"],
+            "m.room.join_rules" if rules >= RedactionRules::V8 => &["join_rule", "allow"],
+            "m.room.join_rules" => &["join_rule"],
+            "m.room.power_levels" if rules >= RedactionRules::V11 => &[
+                "ban",
+                "events",
+                "events_default",
+                "invite",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            "m.room.power_levels" => &[
+                "ban",
+                "events",
+                "events_default",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            "m.room.aliases" if rules < RedactionRules::V6 => &["aliases"],
+            "m.room.history_visibility" => &["history_visibility"],
+            "m.room.redaction" if rules >= RedactionRules::V11 => &["redacts"],
+            _ => &[],
+        };
+
+        let mut redacted = keep_keys(content, keys);
+        if type_ == "m.room.member" && rules >= RedactionRules::V11 {
+            if let Some(signed) = content["third_party_invite"].get("signed") {
+                redacted.insert("third_party_invite".to_owned(), json!({ "signed": signed }));
+            }
+        }
+
+        Value::Object(redacted)
+    }
+}
+
+fn keep_keys(object: &Value, keys: &[&str]) -> Map<String, Value> {
+    object
+        .as_object()
+        .map(|object| {
+            object
+                .iter()
+                .filter(|(key, _)| keys.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get, EventIdFormat, StateResolution, ROOM_VERSIONS};
+    use crate::events::Event;
+    use serde_json::json;
+
+    #[test]
+    fn registry() {
+        let ids: Vec<_> = ROOM_VERSIONS.iter().map(|version| version.id).collect();
+        assert_eq!(
+            ids,
+            vec!["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11"]
+        );
+        assert!(get(super::DEFAULT_ROOM_VERSION).is_some());
+        assert!(get("12").is_none());
+
+        assert_eq!(get("1").unwrap().state_resolution, StateResolution::V1);
+        assert_eq!(get("2").unwrap().state_resolution, StateResolution::V2);
+        assert_eq!(
+            get("3").unwrap().event_id_format,
+            EventIdFormat::ReferenceHash
+        );
+        assert!(get("5").unwrap().special_case_aliases);
+        assert!(!get("6").unwrap().special_case_aliases);
+        assert!(!get("6").unwrap().knocking);
+        assert!(get("7").unwrap().knocking);
+        assert!(get("8").unwrap().restricted_joins);
+        assert!(!get("9").unwrap().integer_power_levels);
+        assert!(get("10").unwrap().integer_power_levels);
+        assert!(get("11").unwrap().implicit_room_creator);
+    }
+
+    #[test]
+    fn redaction() {
+        let member = json!({
+            "type": "m.room.member",
+            "room_id": "!room:x",
+            "sender": "@alice:x",
+            "state_key": "@alice:x",
+            "origin": "x",
+            "unsigned": { "age": 1 },
+            "content": {
+                "membership": "join",
+                "displayname": "Alice",
+                "join_authorised_via_users_server": "@bob:x",
+                "third_party_invite": { "display_name": "alice", "signed": { "token": "t" } },
+            },
+        });
+
+        assert_eq!(
+            get("1").unwrap().redact(&member),
+            json!({
+                "type": "m.room.member",
+                "room_id": "!room:x",
+                "sender": "@alice:x",
+                "state_key": "@alice:x",
+                "origin": "x",
+                "content": { "membership": "join" },
+            })
+        );
+        assert_eq!(
+            get("9").unwrap().redact(&member)["content"],
+            json!({ "membership": "join", "join_authorised_via_users_server": "@bob:x" })
+        );
+        let redacted = get("11").unwrap().redact(&member);
+        assert!(redacted.get("origin").is_none());
+        assert_eq!(
+            redacted["content"]["third_party_invite"],
+            json!({ "signed": { "token": "t" } })
+        );
+
+        let create = json!({
+            "type": "m.room.create",
+            "content": { "creator": "@alice:x", "m.federate": false },
+        });
+        assert_eq!(
+            get("10").unwrap().redact(&create)["content"],
+            json!({ "creator": "@alice:x" })
+        );
+        assert_eq!(
+            get("11").unwrap().redact(&create)["content"],
+            create["content"]
+        );
+
+        let aliases = json!({ "type": "m.room.aliases", "content": { "aliases": ["#a:x"] } });
+        assert_eq!(
+            get("5").unwrap().redact(&aliases)["content"],
+            json!({ "aliases": ["#a:x"] })
+        );
+        assert_eq!(get("6").unwrap().redact(&aliases)["content"], json!({}));
+
+        let join_rules = json!({
+            "type": "m.room.join_rules",
+            "content": { "join_rule": "restricted", "allow": [] },
+        });
+        assert_eq!(
+            get("7").unwrap().redact(&join_rules)["content"],
+            json!({ "join_rule": "restricted" })
+        );
+        assert_eq!(
+            get("8").unwrap().redact(&join_rules)["content"],
+            join_rules["content"]
+        );
+    }
+
+    #[test]
+    fn event_ids() {
+        let mut event = Event {
+            event_id: String::new(),
+            room_id: "!room:x".to_owned(),
+            type_: "m.room.message".to_owned(),
+            state_key: None,
+            sender: "@alice:x".to_owned(),
+            origin_server_ts: 1,
+            content: json!({ "body": "a" }),
+            redacts: None,
+            depth: 2,
+            prev_events: vec!["$previous".to_owned()],
+            auth_events: Vec::new(),
+        };
+
+        let arbitrary = get("2").unwrap().event_id(&event);
+        assert!(arbitrary.starts_with('$') && arbitrary.ends_with(":x"));
+
+        let v3 = get("3").unwrap().event_id(&event);
+        let v4 = get("4").unwrap().event_id(&event);
+        assert_eq!(v3.len(), 44);
+        assert!(!v3.contains(':'));
+        assert_eq!(v4, v3.replace('+', "-").replace('/', "_"));
+        assert_eq!(get("4").unwrap().event_id(&event), v4);
+
+        event.content = json!({ "body": "b" });
+        assert_ne!(get("4").unwrap().event_id(&event), v4);
+    }
+
+    #[test]
+    fn creators() {
+        let create = Event {
+            event_id: "$create".to_owned(),
+            room_id: "!room:x".to_owned(),
+            type_: "m.room.create".to_owned(),
+            state_key: Some(String::new()),
+            sender: "@alice:x".to_owned(),
+            origin_server_ts: 0,
+            content: json!({ "creator": "@bob:x" }),
+            redacts: None,
+            depth: 1,
+            prev_events: Vec::new(),
+            auth_events: Vec::new(),
+        };
+
+        assert_eq!(get("10").unwrap().creator(&create), Some("@bob:x"));
+        assert_eq!(get("11").unwrap().creator(&create), Some("@alice:x"));
+    }
+}
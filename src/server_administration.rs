@@ -1,8 +1,10 @@
 use futures::future;
-use hyper::{Body, Response, StatusCode};
-use serde_json::json;
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::{json, Map, Value};
 
-use crate::{EndpointFutureBox, APPLICATION_JSON};
+use crate::authentication::AuthContext;
+use crate::room_versions::{self, ROOM_VERSIONS};
+use crate::{json_response, EndpointFutureBox, LMServer, APPLICATION_JSON};
 
 /// Returns the versions of the specification supported by the server.
 pub fn versions() -> EndpointFutureBox {
@@ -22,13 +24,51 @@ pub fn versions() -> EndpointFutureBox {
     Box::new(future::ok(resp))
 }
 
+/// Returns the optional features of the specification supported by the server, and the room
+/// versions it can create.
+pub fn capabilities(
+    _server: &LMServer,
+    _auth: AuthContext,
+    _req: Request<Body>,
+) -> EndpointFutureBox {
+    let available: Map<String, Value> = ROOM_VERSIONS
+        .iter()
+        .map(|version| {
+            let stability = if version.stable { "stable" } else { "unstable" };
+            (version.id.to_owned(), json!(stability))
+        })
+        .collect();
+
+    Box::new(future::ok(json_response(json!({
+        "capabilities": {
+            "m.change_password": { "enabled": true },
+            "m.room_versions": {
+                "default": room_versions::DEFAULT_ROOM_VERSION,
+                "available": available,
+            },
+        }
+    }))))
+}
+
 #[cfg(test)]
 mod tests {
-    use futures::Future;
+    use futures::{Future, Stream};
+    use serde_json::{json, Value};
 
     #[test]
     fn versions() {
         let request = crate::server_administration::versions().wait();
         request.unwrap();
     }
+
+    #[test]
+    fn login_opts() {
+        let response = crate::session_management::login_opts().wait().unwrap();
+        let body = response.into_body().concat2().wait().unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "flows": [{ "type": "m.login.password" }, { "type": "m.login.token" }] })
+        );
+    }
 }
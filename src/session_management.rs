@@ -1,14 +1,16 @@
-use bcrypt;
-use hyper;
-use serde_json;
-use uuid;
-
 use futures::{future, Future, IntoFuture, Stream};
-use hyper::{Body, Request, Response};
+use hyper::{Body, Request};
 use serde_derive::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
 
+use crate::authentication::AuthContext;
 use crate::user_data::{create_access_token, generate_device_id};
-use crate::{error_code, tack_on, EndpointFutureBox, ErrorBody, LMServer, APPLICATION_JSON};
+use crate::user_id::UserId;
+use crate::{
+    error_code, json_response, parse_json_body, tack_on, DbPool, EndpointFutureBox, ErrorBody,
+    LMServer,
+};
 
 #[derive(Deserialize)]
 struct LoginReqBody {
@@ -18,13 +20,79 @@ struct LoginReqBody {
     medium: Option<String>,
     // address: Option<String>,
     password: Option<String>,
-    // token: Option<String>,
+    token: Option<String>,
     device_id: Option<String>,
-    // initial_device_display_name: Option<String>,
+    initial_device_display_name: Option<String>,
+}
+
+/// The login types accepted by `login`, as advertised by `login_opts`.
+#[derive(Clone, Copy)]
+enum LoginType {
+    Password,
+    Token,
+}
+
+impl LoginType {
+    const ALL: &'static [LoginType] = &[LoginType::Password, LoginType::Token];
+
+    fn name(self) -> &'static str {
+        match self {
+            LoginType::Password => "m.login.password",
+            LoginType::Token => "m.login.token",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<LoginType> {
+        LoginType::ALL
+            .iter()
+            .cloned()
+            .find(|login_type| login_type.name() == name)
+    }
 }
 
+// Deleting a device also deletes its access tokens.
+const LOGOUT_QUERY: &str = "DELETE FROM devices WHERE user_id=$1 AND id=$2";
+
+const LOGOUT_ALL_QUERY: &str = "DELETE FROM devices WHERE user_id=$1";
+
+const NEW_LOGIN_TOKEN_QUERY: &str = "INSERT INTO login_tokens (token, user_id, expires) \
+                                     VALUES ($1, $2, localtimestamp + make_interval(secs => $3))";
+
+// Login tokens are single-use, so they are deleted whether or not they have expired.
+const USE_LOGIN_TOKEN_QUERY: &str = "WITH used AS (DELETE FROM login_tokens WHERE token=$1 \
+                                     RETURNING user_id, expires) \
+                                     SELECT users.id, users.localpart FROM used \
+                                     INNER JOIN users ON users.id = used.user_id \
+                                     WHERE used.expires > localtimestamp \
+                                     AND NOT users.deactivated";
+
+/// How long a login token may be used for after it is issued.
+const LOGIN_TOKEN_LIFETIME_MS: u64 = 2 * 60 * 1000;
+
+type LoginFutureBox = Box<dyn Future<Item = (uuid::Uuid, String), Error = crate::Error> + Send>;
+
 const INVALID_PASSWORD: ErrorBody =
     ErrorBody::new_static(error_code::M_FORBIDDEN, "Invalid password");
+const USER_DEACTIVATED: ErrorBody = ErrorBody::new_static(
+    error_code::M_USER_DEACTIVATED,
+    "This account has been deactivated",
+);
+const INVALID_LOGIN_TOKEN: ErrorBody =
+    ErrorBody::new_static(error_code::M_FORBIDDEN, "Invalid or expired login token");
+
+fn generate_login_token() -> String {
+    uuid::Uuid::new_v4().to_simple().to_string()
+}
+
+/// Returns the login flows supported by `login`.
+pub fn login_opts() -> EndpointFutureBox {
+    let flows: Vec<_> = LoginType::ALL
+        .iter()
+        .map(|login_type| json!({ "type": login_type.name() }))
+        .collect();
+
+    Box::new(future::ok(json_response(json!({ "flows": flows }))))
+}
 
 pub fn login(server: &LMServer, req: Request<Body>) -> EndpointFutureBox {
     // Request will be of the form:
@@ -50,131 +118,232 @@ pub fn login(server: &LMServer, req: Request<Body>) -> EndpointFutureBox {
     // {
     //   "user_id": "<user_id>",
     //   "access_token": "<access_token>",
-    //   "home_server": "<hostname>",
+    //   "home_server": "<server_name>",
     //   "device_id": "<device_id>"
     // }
 
     let cpupool = server.cpupool.clone();
     let db_pool = server.db_pool.clone();
-    let hostname = server.hostname.clone();
+    let server_name = server.server_name.clone();
     Box::new(
-        req.into_body()
-            .concat2()
-            .map_err(crate::Error::from)
-            .and_then(move |body| {
-                serde_json::from_slice(&body).map_err(|err| {
-                    match err.classify() {
-                        serde_json::error::Category::Syntax
-                        | serde_json::error::Category::Eof
-                        | serde_json::error::Category::Io => ErrorBody::NOT_JSON,
-                        serde_json::error::Category::Data => ErrorBody::BAD_JSON,
-                    }
-                    .into()
-                })
-            })
-            .and_then(move |body: LoginReqBody| -> EndpointFutureBox {
-                if body.type_ == "m.login.password" {
-                    if body.medium.is_some() {
-                        return Box::new(future::err(
-                            ErrorBody::new_static(error_code::M_UNKNOWN, "3pid is not supported")
+        parse_json_body(req.into_body())
+            .and_then({
+                let db_pool = db_pool.clone();
+                let server_name = server_name.clone();
+                move |body: LoginReqBody| {
+                    let req_device = (
+                        body.device_id.clone(),
+                        body.initial_device_display_name.clone(),
+                    );
+                    let user: LoginFutureBox = match LoginType::from_name(&body.type_) {
+                        Some(LoginType::Password) => {
+                            password_login(cpupool, db_pool, server_name, body)
+                        }
+                        Some(LoginType::Token) => token_login(db_pool, body),
+                        None => Box::new(future::err(
+                            ErrorBody::new_static(error_code::M_UNKNOWN, "Unknown login type")
                                 .into(),
-                        ));
-                    }
-
-                    let req_device_id = body.device_id;
-
-                    Box::new(
-                        body.user
-                            .ok_or(ErrorBody::new_static(
-                                error_code::M_UNKNOWN,
-                                "Missing user parameter",
-                            ))
-                            .into_future()
-                            .join(
-                                body.password
-                                    .ok_or(ErrorBody::new_static(
-                                        error_code::M_UNKNOWN,
-                                        "Missing password parameter",
-                                    ))
-                                    .into_future(),
-                            )
-                            .map_err(crate::Error::from)
-                            .and_then(move |(username, password)| {
-                                db_pool
-                                    .run(|mut db| {
-                                        db.prepare(
-                                            "SELECT id, passhash FROM users WHERE localpart=$1",
-                                        )
-                                        .then(|res| tack_on(res, db))
-                                        .and_then(
-                                            move |(q, mut db)| {
-                                                db.query(&q, &[&username])
-                                                    .into_future()
-                                                    .map(|(row, _)| (row, username))
-                                                    .map_err(|(err, _)| err)
-                                                    .then(|res| tack_on(res, db))
-                                            },
-                                        )
-                                    })
-                                    .map_err(crate::Error::from)
-                                    .and_then(|(row, username)| {
-                                        Ok((row.ok_or(INVALID_PASSWORD)?, username))
-                                    })
-                                    .and_then(move |(row, username)| {
-                                        let user_id = row.get(0);
-                                        let passhash: String = row.get(1);
-
-                                        cpupool
-                                            .spawn_fn(move || bcrypt::verify(password, &passhash))
-                                            .map_err(crate::Error::from)
-                                            .and_then(move |correct| {
-                                                if correct {
-                                                    Ok((user_id, username))
-                                                } else {
-                                                    Err(INVALID_PASSWORD.into())
-                                                }
-                                            })
-                                    })
-                                    .and_then(move |(user_id, username): (uuid::Uuid, String)| {
-                                        let device_id =
-                                            req_device_id.unwrap_or_else(generate_device_id);
-                                        db_pool
-                                            .run({
-                                                let device_id = device_id.clone();
-                                                move |db| {
-                                                    create_access_token(db, user_id, device_id)
-                                                }
-                                            })
-                                            .map_err(crate::Error::from)
-                                            .map(move |token| {
-                                                let mut resp = Response::new(
-                                                    serde_json::json!({
-                                                        "user_id": username,
-                                                        "access_token": token,
-                                                        "device_id": device_id,
-                                                        "home_server": *hostname,
-                                                    })
-                                                    .to_string()
-                                                    .into(),
-                                                );
-
-                                                resp.headers_mut().insert(
-                                                    hyper::header::CONTENT_TYPE,
-                                                    hyper::header::HeaderValue::from_static(
-                                                        APPLICATION_JSON,
-                                                    ),
-                                                );
-
-                                                resp
-                                            })
-                                    })
-                            }),
-                    )
-                } else {
-                    Box::new(future::err(
-                        ErrorBody::new_static(error_code::M_UNKNOWN, "Unknown login type").into(),
+                        )),
+                    };
+
+                    user.map(move |user| (user, req_device))
+                }
+            })
+            .and_then(
+                move |((user_id, username), (req_device_id, display_name)): (
+                    (uuid::Uuid, String),
+                    (Option<String>, Option<String>),
+                )| {
+                    let device_id = req_device_id.unwrap_or_else(generate_device_id);
+                    db_pool
+                        .run({
+                            let device_id = device_id.clone();
+                            move |db| create_access_token(db, user_id, device_id, display_name)
+                        })
+                        .map_err(crate::Error::from)
+                        .map(move |token| {
+                            json_response(json!({
+                                "user_id": UserId::new(&username, &server_name).to_string(),
+                                "access_token": token,
+                                "device_id": device_id,
+                                "home_server": *server_name,
+                            }))
+                        })
+                },
+            ),
+    )
+}
+
+/// Checks the user's password, resolving to their ID and localpart.
+fn password_login(
+    cpupool: Arc<futures_cpupool::CpuPool>,
+    db_pool: DbPool,
+    server_name: Arc<String>,
+    body: LoginReqBody,
+) -> LoginFutureBox {
+    if body.medium.is_some() {
+        return Box::new(future::err(
+            ErrorBody::new_static(error_code::M_UNKNOWN, "3pid is not supported").into(),
+        ));
+    }
+
+    Box::new(
+        body.user
+            .ok_or(ErrorBody::new_static(
+                error_code::M_UNKNOWN,
+                "Missing user parameter",
+            ))
+            .into_future()
+            .join(
+                body.password
+                    .ok_or(ErrorBody::new_static(
+                        error_code::M_UNKNOWN,
+                        "Missing password parameter",
                     ))
+                    .into_future(),
+            )
+            .and_then(move |(user, password)| {
+                let user_id = UserId::parse_with_server_name(&user, &server_name)?;
+                // Only local users can log in here.
+                if user_id.server_name() != server_name.as_str() {
+                    return Err(INVALID_PASSWORD);
                 }
+
+                Ok((user_id.localpart().to_owned(), password))
+            })
+            .map_err(crate::Error::from)
+            .and_then(move |(username, password)| {
+                db_pool
+                    .run(|mut db| {
+                        db.prepare("SELECT id, passhash, deactivated FROM users WHERE localpart=$1")
+                            .then(|res| tack_on(res, db))
+                            .and_then(move |(q, mut db)| {
+                                db.query(&q, &[&username])
+                                    .into_future()
+                                    .map(|(row, _)| (row, username))
+                                    .map_err(|(err, _)| err)
+                                    .then(|res| tack_on(res, db))
+                            })
+                    })
+                    .map_err(crate::Error::from)
+                    .and_then(|(row, username)| Ok((row.ok_or(INVALID_PASSWORD)?, username)))
+                    .and_then(move |(row, username)| {
+                        let user_id = row.get(0);
+                        let passhash: String = row.get(1);
+                        let deactivated: bool = row.get(2);
+
+                        cpupool
+                            .spawn_fn(move || bcrypt::verify(password, &passhash))
+                            .map_err(crate::Error::from)
+                            .and_then(move |correct| {
+                                if !correct {
+                                    Err(INVALID_PASSWORD.into())
+                                } else if deactivated {
+                                    Err(USER_DEACTIVATED.into())
+                                } else {
+                                    Ok((user_id, username))
+                                }
+                            })
+                    })
             }),
     )
 }
+
+/// Consumes a login token, resolving to the ID and localpart of the user it was issued to.
+fn token_login(db_pool: DbPool, body: LoginReqBody) -> LoginFutureBox {
+    Box::new(
+        body.token
+            .ok_or(ErrorBody::new_static(
+                error_code::M_UNKNOWN,
+                "Missing token parameter",
+            ))
+            .into_future()
+            .map_err(crate::Error::from)
+            .and_then(move |token| {
+                db_pool
+                    .run(|mut db| {
+                        db.prepare(USE_LOGIN_TOKEN_QUERY)
+                            .then(|res| tack_on(res, db))
+                            .and_then(move |(q, mut db)| {
+                                db.query(&q, &[&token])
+                                    .into_future()
+                                    .map(|(row, _)| row)
+                                    .map_err(|(err, _)| err)
+                                    .then(|res| tack_on(res, db))
+                            })
+                    })
+                    .map_err(crate::Error::from)
+                    .and_then(|row| {
+                        let row = row.ok_or(INVALID_LOGIN_TOKEN)?;
+                        Ok((row.get(0), row.get(1)))
+                    })
+            }),
+    )
+}
+
+/// Issues a short-lived, single-use token which can be exchanged for an access token through
+/// `m.login.token`, e.g. to sign in a new device from an existing session.
+pub fn get_login_token(
+    server: &LMServer,
+    auth: AuthContext,
+    _req: Request<Body>,
+) -> EndpointFutureBox {
+    let token = generate_login_token();
+    Box::new(
+        server
+            .db_pool
+            .run(move |mut db| {
+                db.prepare(NEW_LOGIN_TOKEN_QUERY)
+                    .then(|res| tack_on(res, db))
+                    .and_then(move |(q, mut db)| {
+                        let lifetime = LOGIN_TOKEN_LIFETIME_MS as f64 / 1000.0;
+                        db.execute(&q, &[&token, &auth.user_id, &lifetime])
+                            .map(move |_| token)
+                            .then(|res| tack_on(res, db))
+                    })
+            })
+            .map_err(crate::Error::from)
+            .map(|token| {
+                json_response(json!({
+                    "login_token": token,
+                    "expires_in_ms": LOGIN_TOKEN_LIFETIME_MS,
+                }))
+            }),
+    )
+}
+
+/// Invalidates the calling access token by deleting the device it was issued to.
+pub fn logout(server: &LMServer, auth: AuthContext, _req: Request<Body>) -> EndpointFutureBox {
+    Box::new(
+        server
+            .db_pool
+            .run(move |mut db| {
+                db.prepare(LOGOUT_QUERY)
+                    .then(|res| tack_on(res, db))
+                    .and_then(move |(q, mut db)| {
+                        db.execute(&q, &[&auth.user_id, &auth.device_id])
+                            .then(|res| tack_on(res, db))
+                    })
+            })
+            .map_err(crate::Error::from)
+            .map(|_| json_response(json!({}))),
+    )
+}
+
+/// Invalidates every access token belonging to the calling user, deleting all of their devices.
+pub fn logout_all(server: &LMServer, auth: AuthContext, _req: Request<Body>) -> EndpointFutureBox {
+    Box::new(
+        server
+            .db_pool
+            .run(move |mut db| {
+                db.prepare(LOGOUT_ALL_QUERY)
+                    .then(|res| tack_on(res, db))
+                    .and_then(move |(q, mut db)| {
+                        db.execute(&q, &[&auth.user_id])
+                            .then(|res| tack_on(res, db))
+                    })
+            })
+            .map_err(crate::Error::from)
+            .map(|_| json_response(json!({}))),
+    )
+}
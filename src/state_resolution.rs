@@ -0,0 +1,909 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::event_auth;
+use crate::events::{self, Event, StateMap};
+use crate::power_levels::PowerLevels;
+use crate::room_versions::{RoomVersion, StateResolution};
+
+/// Events by ID, borrowed from the inputs of a resolution.
+type EventMap<'a> = HashMap<&'a str, &'a Event>;
+
+/// Resolves the state of a room where the state sets of several forks of its event graph meet,
+/// using version 2 of the state resolution algorithm.
+///
+/// `events` must contain the auth chains of every event in the state sets; events missing from
+/// it are treated as if they did not exist. Resolves to `None` in rooms whose version uses
+/// another algorithm.
+#[allow(dead_code)]
+pub fn resolve(
+    version: &RoomVersion,
+    state_sets: &[StateMap],
+    events: &HashMap<String, Event>,
+) -> Option<StateMap> {
+    if version.state_resolution != StateResolution::V2 {
+        return None;
+    }
+
+    let mut event_map: EventMap = events
+        .iter()
+        .map(|(event_id, event)| (event_id.as_str(), event))
+        .collect();
+    for event in state_sets.iter().flat_map(StateMap::values) {
+        event_map.entry(&event.event_id).or_insert(event);
+    }
+
+    let (unconflicted, conflicted) = split_conflicted(state_sets);
+    if conflicted.is_empty() {
+        return Some(unconflicted);
+    }
+
+    let mut full_conflicted = conflicted;
+    full_conflicted.extend(auth_difference(state_sets, &event_map));
+    full_conflicted.retain(|event_id| event_map.contains_key(event_id));
+
+    // Power events are resolved first, together with the conflicted events they depend on.
+    let power_events: HashSet<&str> = full_conflicted
+        .iter()
+        .filter(|&&event_id| is_power_event(event_map[event_id]))
+        .flat_map(|&event_id| {
+            let mut events = auth_chain(vec![event_id], &event_map);
+            events.retain(|auth_id| full_conflicted.contains(auth_id));
+            events.insert(event_id);
+            events
+        })
+        .collect();
+
+    let power_order = reverse_topological_power_order(version, &power_events, &event_map);
+    let mut state = iterative_auth_checks(version, &power_order, unconflicted.clone(), &event_map);
+    state.extend(unconflicted.clone());
+
+    let power_levels = events::get_state(&state, "m.room.power_levels", "")
+        .and_then(|event| event_map.get(event.event_id.as_str()).cloned());
+    let others = full_conflicted
+        .iter()
+        .filter(|event_id| !power_events.contains(*event_id))
+        .map(|&event_id| event_map[event_id])
+        .collect();
+    let other_order = mainline_order(others, power_levels, &event_map);
+    let mut state = iterative_auth_checks(version, &other_order, state, &event_map);
+    state.extend(unconflicted);
+
+    Some(state)
+}
+
+/// Splits the state sets into the state they all agree on and the IDs of the events for every
+/// other key, including keys missing from some of the sets.
+fn split_conflicted(state_sets: &[StateMap]) -> (StateMap, HashSet<&str>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = HashSet::new();
+
+    let keys: HashSet<_> = state_sets.iter().flat_map(StateMap::keys).collect();
+    for key in keys {
+        let event_ids: HashSet<Option<&str>> = state_sets
+            .iter()
+            .map(|state| state.get(key).map(|event| event.event_id.as_str()))
+            .collect();
+
+        if event_ids.len() == 1 {
+            unconflicted.insert(key.clone(), state_sets[0][key].clone());
+        } else {
+            conflicted.extend(event_ids.into_iter().flatten());
+        }
+    }
+
+    (unconflicted, conflicted)
+}
+
+/// Collects the IDs of every event reachable through the auth events of `event_ids`, excluding
+/// the events themselves unless they authorise each other.
+fn auth_chain<'a>(event_ids: Vec<&'a str>, events: &EventMap<'a>) -> HashSet<&'a str> {
+    let mut chain = HashSet::new();
+    let mut pending = event_ids;
+
+    while let Some(event_id) = pending.pop() {
+        if let Some(event) = events.get(event_id) {
+            for auth_id in &event.auth_events {
+                if chain.insert(auth_id.as_str()) {
+                    pending.push(auth_id);
+                }
+            }
+        }
+    }
+
+    chain
+}
+
+/// The events which are in the auth chains of some, but not all, of the state sets.
+fn auth_difference<'a>(state_sets: &'a [StateMap], events: &EventMap<'a>) -> HashSet<&'a str> {
+    let chains: Vec<HashSet<&str>> = state_sets
+        .iter()
+        .map(|state| {
+            let event_ids = state
+                .values()
+                .map(|event| event.event_id.as_str())
+                .collect();
+            auth_chain(event_ids, events)
+        })
+        .collect();
+
+    chains
+        .iter()
+        .flatten()
+        .filter(|event_id| !chains.iter().all(|chain| chain.contains(*event_id)))
+        .cloned()
+        .collect()
+}
+
+/// Whether `event` can take away the abilities of other users.
+fn is_power_event(event: &Event) -> bool {
+    match event.type_.as_str() {
+        "m.room.create" | "m.room.power_levels" | "m.room.join_rules" => {
+            event.state_key.as_deref() == Some("")
+        }
+        "m.room.member" => {
+            let membership = event.membership();
+            (membership == Some("leave") || membership == Some("ban"))
+                && event.state_key.as_deref() != Some(event.sender.as_str())
+        }
+        _ => false,
+    }
+}
+
+/// The event of type `type_` with an empty state key among the auth events of `event`.
+fn auth_event<'a>(event: &Event, type_: &str, events: &EventMap<'a>) -> Option<&'a Event> {
+    event
+        .auth_events
+        .iter()
+        .filter_map(|auth_id| events.get(auth_id.as_str()))
+        .find(|auth_event| auth_event.type_ == type_ && auth_event.state_key.as_deref() == Some(""))
+        .cloned()
+}
+
+/// The power level of the sender of `event`, according to its own auth events.
+fn sender_power_level(version: &RoomVersion, event: &Event, events: &EventMap) -> i64 {
+    let power_levels = match auth_event(event, "m.room.power_levels", events) {
+        Some(power_levels) => PowerLevels::from_content(&power_levels.content),
+        None => PowerLevels::without_event(
+            auth_event(event, "m.room.create", events).and_then(|create| version.creator(create)),
+        ),
+    };
+
+    power_levels.user_level(&event.sender)
+}
+
+/// Sorts `event_ids` so that every event comes after its auth events, breaking ties by
+/// descending sender power level, then timestamp, then event ID.
+fn reverse_topological_power_order<'a>(
+    version: &RoomVersion,
+    event_ids: &HashSet<&'a str>,
+    events: &EventMap<'a>,
+) -> Vec<&'a Event> {
+    let mut unsorted_auth_events = HashMap::new();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for &event_id in event_ids {
+        let auth_ids: HashSet<&str> = events[event_id]
+            .auth_events
+            .iter()
+            .map(String::as_str)
+            .filter(|auth_id| event_ids.contains(auth_id))
+            .collect();
+        for &auth_id in &auth_ids {
+            dependents.entry(auth_id).or_default().push(event_id);
+        }
+        unsorted_auth_events.insert(event_id, auth_ids.len());
+    }
+
+    let sort_key = |event_id: &'a str| {
+        let event = events[event_id];
+        Reverse((
+            -sender_power_level(version, event, events),
+            event.origin_server_ts,
+            event_id,
+        ))
+    };
+
+    let mut ready: BinaryHeap<_> = unsorted_auth_events
+        .iter()
+        .filter(|(_, &count)| count == 0)
+        .map(|(&event_id, _)| sort_key(event_id))
+        .collect();
+
+    let mut sorted = Vec::new();
+    while let Some(Reverse((_, _, event_id))) = ready.pop() {
+        sorted.push(events[event_id]);
+        for &dependent in dependents.get(event_id).into_iter().flatten() {
+            let count = unsorted_auth_events.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(sort_key(dependent));
+            }
+        }
+    }
+
+    sorted
+}
+
+/// Sorts `unsorted` by the position of their closest ancestor on the mainline of
+/// `power_levels`, then timestamp, then event ID.
+///
+/// The mainline of a power levels event is the chain of power levels events it was authorised
+/// by; events without an ancestor on it come first.
+fn mainline_order<'a>(
+    mut unsorted: Vec<&'a Event>,
+    power_levels: Option<&'a Event>,
+    events: &EventMap<'a>,
+) -> Vec<&'a Event> {
+    let mut mainline = Vec::new();
+    let mut next = power_levels;
+    while let Some(event) = next {
+        if mainline.contains(&event.event_id.as_str()) {
+            break;
+        }
+        mainline.push(event.event_id.as_str());
+        next = auth_event(event, "m.room.power_levels", events);
+    }
+
+    let positions: HashMap<&str, usize> = mainline
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, &event_id)| (event_id, index + 1))
+        .collect();
+
+    let mainline_position = |event: &Event| {
+        let mut visited = HashSet::new();
+        let mut next = Some(event);
+        while let Some(event) = next {
+            if let Some(&position) = positions.get(event.event_id.as_str()) {
+                return position;
+            }
+            if !visited.insert(event.event_id.as_str()) {
+                break;
+            }
+            next = auth_event(event, "m.room.power_levels", events);
+        }
+        0
+    };
+
+    unsorted.sort_by_cached_key(|event| {
+        (
+            mainline_position(event),
+            event.origin_server_ts,
+            event.event_id.as_str(),
+        )
+    });
+    unsorted
+}
+
+/// Applies `sorted` to `state` in order, skipping any event which is not allowed by its own
+/// auth events combined with the state resolved so far.
+fn iterative_auth_checks(
+    version: &RoomVersion,
+    sorted: &[&Event],
+    mut state: StateMap,
+    events: &EventMap,
+) -> StateMap {
+    for &event in sorted {
+        let mut auth_state = events::state_map(
+            event
+                .auth_events
+                .iter()
+                .filter_map(|auth_id| events.get(auth_id.as_str()))
+                .map(|&auth_event| auth_event.clone())
+                .collect(),
+        );
+        let auth_types = events::auth_types(
+            &event.type_,
+            event.state_key.as_deref(),
+            &event.sender,
+            &event.content,
+        );
+        for key in auth_types {
+            if let Some(resolved) = state.get(&key) {
+                auth_state.insert(key, resolved.clone());
+            }
+        }
+
+        if event_auth::check(version, event, &auth_state).is_ok() {
+            if let Some(ref state_key) = event.state_key {
+                state.insert((event.type_.clone(), state_key.clone()), event.clone());
+            }
+        }
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_versions;
+    use serde_json::{json, Value};
+
+    const ROOM_ID: &str = "!room:example.com";
+    const ALICE: &str = "@alice:example.com";
+    const BOB: &str = "@bob:example.com";
+    const CHARLIE: &str = "@charlie:example.com";
+    const EVELYN: &str = "@evelyn:example.com";
+
+    /// The tip of one fork of a room and the state after it.
+    #[derive(Clone, Default)]
+    struct Fork {
+        state: StateMap,
+        head: Vec<String>,
+    }
+
+    /// Every event created by a test, with timestamps in the order they were added.
+    #[derive(Default)]
+    struct Graph {
+        events: HashMap<String, Event>,
+    }
+
+    impl Graph {
+        /// Adds an event on top of `fork`, authorised by the state of the fork.
+        fn add(
+            &mut self,
+            fork: &mut Fork,
+            name: &str,
+            sender: &str,
+            type_: &str,
+            state_key: Option<&str>,
+            content: Value,
+        ) {
+            let auth_events = events::auth_types(type_, state_key, sender, &content)
+                .iter()
+                .filter_map(|key| fork.state.get(key))
+                .map(|event| event.event_id.clone())
+                .collect();
+            let event = Event {
+                event_id: id(name),
+                room_id: ROOM_ID.to_owned(),
+                type_: type_.to_owned(),
+                state_key: state_key.map(str::to_owned),
+                sender: sender.to_owned(),
+                origin_server_ts: self.events.len() as i64,
+                content,
+                redacts: None,
+                depth: self.events.len() as i64 + 1,
+                prev_events: fork.head.clone(),
+                auth_events,
+            };
+            assert_eq!(
+                event_auth::check(version(), &event, &fork.state),
+                Ok(()),
+                "{}",
+                name
+            );
+
+            fork.head = vec![event.event_id.clone()];
+            if let Some(state_key) = state_key {
+                fork.state
+                    .insert((type_.to_owned(), state_key.to_owned()), event.clone());
+            }
+            self.events.insert(event.event_id.clone(), event);
+        }
+
+        fn event_map(&self) -> EventMap<'_> {
+            self.events
+                .iter()
+                .map(|(event_id, event)| (event_id.as_str(), event))
+                .collect()
+        }
+    }
+
+    fn version() -> &'static RoomVersion {
+        room_versions::get("10").unwrap()
+    }
+
+    fn resolve(state_sets: &[StateMap], events: &HashMap<String, Event>) -> StateMap {
+        super::resolve(version(), state_sets, events).unwrap()
+    }
+
+    fn id(name: &str) -> String {
+        format!("${}", name)
+    }
+
+    fn ids<'a>(events: &[&'a Event]) -> Vec<&'a str> {
+        events.iter().map(|event| event.event_id.as_str()).collect()
+    }
+
+    fn event_id<'a>(state: &'a StateMap, type_: &str, state_key: &str) -> Option<&'a str> {
+        events::get_state(state, type_, state_key).map(|event| event.event_id.as_str())
+    }
+
+    fn member(membership: &str) -> Value {
+        json!({ "membership": membership })
+    }
+
+    fn topic(topic: &str) -> Value {
+        json!({ "topic": topic })
+    }
+
+    /// A public room created by Alice, who has power level 100, where Bob has power level 50 and
+    /// Charlie has none.
+    fn room(graph: &mut Graph) -> Fork {
+        let mut fork = Fork::default();
+        graph.add(
+            &mut fork,
+            "create",
+            ALICE,
+            "m.room.create",
+            Some(""),
+            json!({ "creator": ALICE }),
+        );
+        graph.add(
+            &mut fork,
+            "ima",
+            ALICE,
+            "m.room.member",
+            Some(ALICE),
+            member("join"),
+        );
+        graph.add(
+            &mut fork,
+            "ipower",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50 } }),
+        );
+        graph.add(
+            &mut fork,
+            "ijr",
+            ALICE,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "public" }),
+        );
+        graph.add(
+            &mut fork,
+            "imb",
+            BOB,
+            "m.room.member",
+            Some(BOB),
+            member("join"),
+        );
+        graph.add(
+            &mut fork,
+            "imc",
+            CHARLIE,
+            "m.room.member",
+            Some(CHARLIE),
+            member("join"),
+        );
+        fork
+    }
+
+    #[test]
+    fn power_events() {
+        let mut graph = Graph::default();
+        let mut fork = room(&mut graph);
+        graph.add(
+            &mut fork,
+            "leave",
+            CHARLIE,
+            "m.room.member",
+            Some(CHARLIE),
+            member("leave"),
+        );
+        graph.add(
+            &mut fork,
+            "invite",
+            ALICE,
+            "m.room.member",
+            Some(CHARLIE),
+            member("invite"),
+        );
+        graph.add(
+            &mut fork,
+            "kick",
+            ALICE,
+            "m.room.member",
+            Some(CHARLIE),
+            member("leave"),
+        );
+        graph.add(
+            &mut fork,
+            "ban",
+            ALICE,
+            "m.room.member",
+            Some(EVELYN),
+            member("ban"),
+        );
+        graph.add(
+            &mut fork,
+            "topic",
+            ALICE,
+            "m.room.topic",
+            Some(""),
+            topic("x"),
+        );
+
+        let is_power_event = |name: &str| is_power_event(&graph.events[&id(name)]);
+        assert!(is_power_event("create"));
+        assert!(is_power_event("ipower"));
+        assert!(is_power_event("ijr"));
+        assert!(is_power_event("kick"));
+        assert!(is_power_event("ban"));
+        assert!(!is_power_event("ima"));
+        assert!(!is_power_event("leave"));
+        assert!(!is_power_event("invite"));
+        assert!(!is_power_event("topic"));
+    }
+
+    #[test]
+    fn conflicted_state() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(&mut a, "ta", ALICE, "m.room.topic", Some(""), topic("a"));
+        graph.add(
+            &mut a,
+            "name",
+            ALICE,
+            "m.room.name",
+            Some(""),
+            json!({ "name": "a" }),
+        );
+        let mut b = base.clone();
+        graph.add(&mut b, "tb", BOB, "m.room.topic", Some(""), topic("b"));
+
+        let state_sets = [a.state, b.state];
+        let (unconflicted, conflicted) = split_conflicted(&state_sets);
+        assert_eq!(unconflicted, base.state);
+        let expected: HashSet<&str> = vec!["$ta", "$tb", "$name"].into_iter().collect();
+        assert_eq!(conflicted, expected);
+    }
+
+    #[test]
+    fn unconflicted_state() {
+        let mut graph = Graph::default();
+        let fork = room(&mut graph);
+
+        let v1 = room_versions::get("1").unwrap();
+        assert_eq!(super::resolve(v1, &[], &graph.events), None);
+
+        assert!(resolve(&[], &graph.events).is_empty());
+        assert_eq!(
+            resolve(std::slice::from_ref(&fork.state), &graph.events),
+            fork.state
+        );
+        assert_eq!(
+            resolve(&[fork.state.clone(), fork.state.clone()], &graph.events),
+            fork.state
+        );
+    }
+
+    #[test]
+    fn state_missing_from_a_fork() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(&mut a, "t", BOB, "m.room.topic", Some(""), topic("a"));
+
+        let resolved = resolve(&[a.state.clone(), base.state], &graph.events);
+        assert_eq!(resolved, a.state);
+    }
+
+    #[test]
+    fn auth_chains() {
+        let mut graph = Graph::default();
+        let mut fork = room(&mut graph);
+        graph.add(&mut fork, "t", BOB, "m.room.topic", Some(""), topic("a"));
+
+        let event_map = graph.event_map();
+        let chain = auth_chain(vec!["$t"], &event_map);
+        let expected: HashSet<&str> = vec!["$create", "$ipower", "$imb", "$ima", "$ijr"]
+            .into_iter()
+            .collect();
+        assert_eq!(chain, expected);
+        assert!(auth_chain(vec!["$create"], &event_map).is_empty());
+    }
+
+    #[test]
+    fn auth_differences() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(
+            &mut a,
+            "pa1",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 10 } }),
+        );
+        graph.add(
+            &mut a,
+            "pa2",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 20 } }),
+        );
+
+        let state_sets = [a.state.clone(), base.state.clone()];
+        let event_map = graph.event_map();
+        let expected: HashSet<&str> = vec!["$pa1"].into_iter().collect();
+        assert_eq!(auth_difference(&state_sets, &event_map), expected);
+
+        let resolved = resolve(&state_sets, &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.power_levels", ""), Some("$pa2"));
+    }
+
+    #[test]
+    fn power_ordering() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(
+            &mut a,
+            "jr",
+            BOB,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "invite" }),
+        );
+        let mut b = base.clone();
+        graph.add(
+            &mut b,
+            "pa",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 10 } }),
+        );
+        graph.add(
+            &mut b,
+            "jr2",
+            ALICE,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "public" }),
+        );
+        let mut c = base;
+        graph.add(
+            &mut c,
+            "ban",
+            BOB,
+            "m.room.member",
+            Some(EVELYN),
+            member("ban"),
+        );
+
+        let event_map = graph.event_map();
+        let event_ids = vec!["$jr", "$pa", "$jr2", "$ban"].into_iter().collect();
+        assert_eq!(
+            ids(&reverse_topological_power_order(
+                version(),
+                &event_ids,
+                &event_map
+            )),
+            vec!["$pa", "$jr2", "$jr", "$ban"]
+        );
+
+        let event_ids = vec!["$create", "$ipower", "$ima"].into_iter().collect();
+        assert_eq!(
+            ids(&reverse_topological_power_order(
+                version(),
+                &event_ids,
+                &event_map
+            )),
+            vec!["$create", "$ima", "$ipower"]
+        );
+    }
+
+    #[test]
+    fn mainline_ordering() {
+        let mut graph = Graph::default();
+        let mut base = room(&mut graph);
+        graph.add(&mut base, "t0", ALICE, "m.room.topic", Some(""), topic("0"));
+        graph.add(
+            &mut base,
+            "pa1",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 10 } }),
+        );
+        let mut a = base.clone();
+        graph.add(&mut a, "t1", ALICE, "m.room.topic", Some(""), topic("1"));
+        graph.add(
+            &mut a,
+            "pa2",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 20 } }),
+        );
+        graph.add(&mut a, "t2", ALICE, "m.room.topic", Some(""), topic("2"));
+        let mut b = base;
+        graph.add(&mut b, "t3", BOB, "m.room.topic", Some(""), topic("3"));
+
+        let event_map = graph.event_map();
+        let unsorted = vec!["$t2", "$t3", "$t1", "$t0"]
+            .into_iter()
+            .map(|event_id| event_map[event_id])
+            .collect();
+        assert_eq!(
+            ids(&mainline_order(
+                unsorted,
+                Some(event_map["$pa2"]),
+                &event_map
+            )),
+            vec!["$t0", "$t1", "$t3", "$t2"]
+        );
+
+        let unsorted = vec!["$t3", "$t0"]
+            .into_iter()
+            .map(|event_id| event_map[event_id])
+            .collect();
+        assert_eq!(
+            ids(&mainline_order(unsorted, None, &event_map)),
+            vec!["$t0", "$t3"]
+        );
+    }
+
+    #[test]
+    fn ban_against_state_change() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(
+            &mut a,
+            "mb",
+            ALICE,
+            "m.room.member",
+            Some(BOB),
+            member("ban"),
+        );
+        let mut b = base;
+        graph.add(
+            &mut b,
+            "jr",
+            BOB,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "invite" }),
+        );
+
+        let resolved = resolve(&[a.state, b.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.member", BOB), Some("$mb"));
+        assert_eq!(event_id(&resolved, "m.room.join_rules", ""), Some("$ijr"));
+    }
+
+    #[test]
+    fn join_rule_evasion() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(
+            &mut a,
+            "jr",
+            ALICE,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "invite" }),
+        );
+        let mut b = base;
+        graph.add(
+            &mut b,
+            "me",
+            EVELYN,
+            "m.room.member",
+            Some(EVELYN),
+            member("join"),
+        );
+
+        let resolved = resolve(&[a.state, b.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.join_rules", ""), Some("$jr"));
+        assert_eq!(event_id(&resolved, "m.room.member", EVELYN), None);
+    }
+
+    #[test]
+    fn offtopic_power_levels() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(
+            &mut a,
+            "pb",
+            BOB,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 50 } }),
+        );
+        graph.add(
+            &mut a,
+            "pc",
+            CHARLIE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 0 } }),
+        );
+
+        let resolved = resolve(&[base.state, a.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.power_levels", ""), Some("$pc"));
+    }
+
+    #[test]
+    fn topic_after_demotion() {
+        let mut graph = Graph::default();
+        let mut base = room(&mut graph);
+        graph.add(&mut base, "t1", ALICE, "m.room.topic", Some(""), topic("1"));
+        graph.add(
+            &mut base,
+            "pa1",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50 } }),
+        );
+        let mut a = base.clone();
+        graph.add(&mut a, "t2", ALICE, "m.room.topic", Some(""), topic("2"));
+        graph.add(
+            &mut a,
+            "pa2",
+            ALICE,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 0 } }),
+        );
+        let mut b = base;
+        graph.add(
+            &mut b,
+            "pb",
+            BOB,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { ALICE: 100, BOB: 50 } }),
+        );
+        graph.add(&mut b, "t3", BOB, "m.room.topic", Some(""), topic("3"));
+
+        let resolved = resolve(&[a.state, b.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.power_levels", ""), Some("$pa2"));
+        assert_eq!(event_id(&resolved, "m.room.topic", ""), Some("$t2"));
+    }
+
+    #[test]
+    fn topic_reset_by_ban() {
+        let mut graph = Graph::default();
+        let mut base = room(&mut graph);
+        graph.add(&mut base, "t1", ALICE, "m.room.topic", Some(""), topic("1"));
+        let mut a = base.clone();
+        graph.add(&mut a, "t2", BOB, "m.room.topic", Some(""), topic("2"));
+        graph.add(
+            &mut a,
+            "mb",
+            ALICE,
+            "m.room.member",
+            Some(BOB),
+            member("ban"),
+        );
+
+        let resolved = resolve(&[a.state, base.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.topic", ""), Some("$t1"));
+        assert_eq!(event_id(&resolved, "m.room.member", BOB), Some("$mb"));
+        assert_eq!(
+            event_id(&resolved, "m.room.power_levels", ""),
+            Some("$ipower")
+        );
+    }
+
+    #[test]
+    fn concurrent_topics_by_timestamp() {
+        let mut graph = Graph::default();
+        let base = room(&mut graph);
+        let mut a = base.clone();
+        graph.add(&mut a, "t1", ALICE, "m.room.topic", Some(""), topic("1"));
+        let mut b = base;
+        graph.add(&mut b, "t2", BOB, "m.room.topic", Some(""), topic("2"));
+
+        // Both topics are allowed, so the later one wins regardless of the order of the forks.
+        let resolved = resolve(&[a.state.clone(), b.state.clone()], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.topic", ""), Some("$t2"));
+        let resolved = resolve(&[b.state, a.state], &graph.events);
+        assert_eq!(event_id(&resolved, "m.room.topic", ""), Some("$t2"));
+    }
+}
@@ -1,16 +1,54 @@
-use futures::{future, Future, Stream};
-use hyper::{Body, Request, Response, StatusCode};
-use regex::Regex;
+use futures::{future, Future};
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
 use serde_json::json;
+use tokio_postgres::error::SqlState;
 
-use crate::{error_code, tack_on, EndpointFutureBox, ErrorBody, LMServer, APPLICATION_JSON};
+use crate::authentication::AuthContext;
+use crate::db;
+use crate::profile;
+use crate::user_id::{self, UserId};
+use crate::user_interactive_auth::{self, Flow};
+use crate::{
+    error_code, json_response, parse_json_body, tack_on, EndpointFutureBox, ErrorBody, LMServer,
+};
 
-const REGISTER_QUERY: &'static str =
-    "INSERT INTO users (id, localpart, passhash) VALUES ($1, $2, $3)";
+const REGISTER_QUERY: &str = "INSERT INTO users (id, localpart, passhash) VALUES ($1, $2, $3)";
 
-const NEW_TOKEN_QUERY: &'static str =
+// The display name of an existing device is left alone.
+const NEW_DEVICE_QUERY: &str = "INSERT INTO devices (user_id, id, display_name) \
+                                VALUES ($1, $2, $3) ON CONFLICT DO NOTHING";
+
+const NEW_TOKEN_QUERY: &str =
     "INSERT INTO tokens (id, user_id, created, device_id) VALUES ($1, $2, localtimestamp, $3)";
 
+const CHANGE_PASSWORD_QUERY: &str = "UPDATE users SET passhash=$2 WHERE id=$1";
+
+// Deleting a device also deletes its access tokens.
+const LOGOUT_OTHER_DEVICES_QUERY: &str = "DELETE FROM devices WHERE user_id=$1 AND id<>$2";
+
+const LOGOUT_OTHER_TOKENS_QUERY: &str = "DELETE FROM tokens WHERE user_id=$1 AND id<>$2";
+
+const DEACTIVATE_QUERY: &str =
+    "UPDATE users SET deactivated=true, erased=(erased OR $2) WHERE id=$1";
+
+const LOGOUT_USER_QUERY: &str = "DELETE FROM devices WHERE user_id=$1";
+
+const DELETE_LOGIN_TOKENS_QUERY: &str = "DELETE FROM login_tokens WHERE user_id=$1";
+
+const REGISTER_FLOWS: &[Flow] = &[&[user_interactive_auth::DUMMY]];
+
+const PASSWORD_FLOWS: &[Flow] = &[&[user_interactive_auth::PASSWORD]];
+
+const LOCALPART_IN_USE_QUERY: &str = "SELECT 1 FROM users WHERE localpart=$1";
+
+const USER_IN_USE: ErrorBody =
+    ErrorBody::new_static(error_code::M_USER_IN_USE, "User ID already taken");
+const RESERVED_USERNAME: ErrorBody =
+    ErrorBody::new_static(error_code::M_EXCLUSIVE, "User ID is reserved");
+const MISSING_PASSWORD: ErrorBody =
+    ErrorBody::new_static(error_code::M_MISSING_PARAM, "Missing password parameter");
+
 fn generate_access_token() -> uuid::Uuid {
     uuid::Uuid::new_v4()
 }
@@ -19,132 +57,331 @@ pub fn generate_device_id() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
+/// Issues a new access token for `device_id`, registering the device if it is new.
 pub fn create_access_token(
-    mut db: tokio_postgres::Client,
+    db: tokio_postgres::Client,
     user_id: uuid::Uuid,
     device_id: String,
+    display_name: Option<String>,
 ) -> impl Future<
     Item = (String, tokio_postgres::Client),
     Error = (tokio_postgres::Error, tokio_postgres::Client),
 > {
     let token = generate_access_token();
-    db.prepare(NEW_TOKEN_QUERY)
-        .then(|res| tack_on(res, db))
-        .and_then(
-            move |(q, mut db): (tokio_postgres::Statement, tokio_postgres::Client)| {
-                db.execute(&q, &[&token, &user_id, &device_id])
-                    .and_then(move |_| Ok(token.to_string()))
-                    .then(|res| tack_on(res, db))
-            },
-        )
+    db::execute(
+        db,
+        NEW_DEVICE_QUERY,
+        params![user_id, device_id.clone(), display_name],
+    )
+    .and_then(move |(_, db)| {
+        db::execute(db, NEW_TOKEN_QUERY, params![token, user_id, device_id])
+            .map(move |(_, db)| (token.to_string(), db))
+    })
 }
 
-pub fn register(server: &LMServer, req: Request<Body>) -> EndpointFutureBox {
-    let cpupool = server.cpupool.clone();
-    let db_pool = server.db_pool.clone();
-    let hostname = server.hostname.clone();
+/// Checks that `localpart` is valid and not reserved, without checking whether it is in use.
+fn check_localpart(server: &LMServer, localpart: &str) -> Result<(), ErrorBody> {
+    if !user_id::is_valid_localpart(localpart) {
+        return Err(ErrorBody::INVALID_USERNAME);
+    }
+    if server
+        .reserved_localparts
+        .iter()
+        .any(|pattern| pattern.is_match(localpart))
+    {
+        return Err(RESERVED_USERNAME);
+    }
+
+    Ok(())
+}
 
+fn check_localpart_unused(
+    server: &LMServer,
+    localpart: String,
+) -> impl Future<Item = (), Error = crate::Error> {
+    server
+        .db_pool
+        .run(move |db| db::query_opt(db, LOCALPART_IN_USE_QUERY, params![localpart]))
+        .map_err(crate::Error::from)
+        .and_then(|row| match row {
+            Some(_) => Err(USER_IN_USE.into()),
+            None => Ok(()),
+        })
+}
+
+/// Checks whether a username can be registered, so that clients can report problems before
+/// walking through user-interactive authentication.
+pub fn register_available(server: &LMServer, req: Request<Body>) -> EndpointFutureBox {
     let query = qstring::QString::from(req.uri().query().unwrap_or(""));
-    // TODO: Move this into a helper function as it is used elsewhere
-    Box::new(req.into_body().concat2().map_err(crate::Error::from).and_then(move |body| {
-        serde_json::from_slice(&body)
-            .map_err(|err| {
-            match err.classify() {
-                serde_json::error::Category::Syntax
-                | serde_json::error::Category::Eof
-                | serde_json::error::Category::Io => {
-                    ErrorBody::NOT_JSON
-                }
-                serde_json::error::Category::Data => {
-                    ErrorBody::BAD_JSON
+    let username = query.get("username").unwrap_or("").to_owned();
+    if let Err(err) = check_localpart(server, &username) {
+        return Box::new(future::err(err.into()));
+    }
+
+    Box::new(
+        check_localpart_unused(server, username)
+            .map(|()| json_response(json!({ "available": true }))),
+    )
+}
+
+pub fn register(server: &LMServer, req: Request<Body>) -> EndpointFutureBox {
+    let server = server.clone();
+    let query = qstring::QString::from(req.uri().query().unwrap_or(""));
+
+    Box::new(parse_json_body(req.into_body()).and_then(
+        move |body: serde_json::Value| -> EndpointFutureBox {
+            match query.get("kind").unwrap_or("user") {
+                "guest" => Box::new(future::err(ErrorBody::GUEST_ACCESS_FORBIDDEN.into())),
+                "user" => {
+                    let username = body["username"].as_str().unwrap_or("").to_owned();
+                    let password = body["password"].as_str().map(str::to_owned);
+                    let req_device_id = body["device_id"].as_str().map(|x| x.to_owned());
+                    let device_display_name = body["initial_device_display_name"]
+                        .as_str()
+                        .map(|x| x.to_owned());
+
+                    let cpupool = server.cpupool.clone();
+                    let db_pool = server.db_pool.clone();
+                    let server_name = server.server_name.clone();
+                    let auth = body.get("auth").cloned();
+                    Box::new(
+                        user_interactive_auth::check(&server, REGISTER_FLOWS, None, auth)
+                            .and_then({
+                                let server = server.clone();
+                                let username = username.clone();
+                                // Clients probe for the flows with an empty body, so the rest of
+                                // it is only validated once authentication is complete.
+                                move |()| -> Box<
+                                    dyn Future<Item = String, Error = crate::Error> + Send,
+                                > {
+                                    if let Err(err) = check_localpart(&server, &username) {
+                                        return Box::new(future::err(err.into()));
+                                    }
+                                    let password = match password {
+                                        Some(password) => password,
+                                        None => {
+                                            return Box::new(future::err(MISSING_PASSWORD.into()))
+                                        }
+                                    };
+                                    Box::new(
+                                        check_localpart_unused(&server, username)
+                                            .map(move |()| password),
+                                    )
+                                }
+                            })
+                            .and_then(move |password| {
+                                println!("Hashing password...");
+                                cpupool
+                                    .spawn_fn(move || bcrypt::hash(&password, bcrypt::DEFAULT_COST))
+                                    .map_err(crate::Error::from)
+                            })
+                            .and_then(move |hash| {
+                                db_pool
+                                    .run(|mut db| {
+                                        db.prepare(REGISTER_QUERY)
+                                            .then(|res| tack_on(res, db))
+                                            .and_then(move |(q, mut db)| {
+                                                let id = uuid::Uuid::new_v4();
+                                                {
+                                                    let values: Vec<
+                                                        &dyn tokio_postgres::types::ToSql,
+                                                    > = vec![&id, &username, &hash];
+                                                    db.execute(&q, &values)
+                                                }
+                                                .and_then(move |_| Ok((id, username)))
+                                                .then(|res| tack_on(res, db))
+                                            })
+                                            .and_then(|((user_id, username), db)| {
+                                                // New users start out displayed by their localpart.
+                                                db::execute(
+                                                    db,
+                                                    profile::NEW_PROFILE_QUERY,
+                                                    params![user_id, username.clone()],
+                                                )
+                                                .map(move |(_, db)| ((user_id, username), db))
+                                            })
+                                            .and_then(move |((user_id, username), db)| {
+                                                let device_id = req_device_id
+                                                    .unwrap_or_else(generate_device_id);
+                                                create_access_token(
+                                                    db,
+                                                    user_id,
+                                                    device_id.clone(),
+                                                    device_display_name,
+                                                )
+                                                .and_then(|(token, db)| {
+                                                    Ok(((token, device_id, username), db))
+                                                })
+                                            })
+                                    })
+                                    .map_err(|err| match err {
+                                        // Someone else registered the username since it was checked.
+                                        bb8::RunError::User(ref err)
+                                            if err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                                        {
+                                            USER_IN_USE.into()
+                                        }
+                                        err => crate::Error::from(err),
+                                    })
+                            })
+                            .map(move |(token, device_id, username)| {
+                                json_response(json!({
+                                    "user_id": UserId::new(&username, &server_name).to_string(),
+                                    "access_token": token,
+                                    "device_id": device_id,
+                                    "home_server": *server_name
+                                }))
+                            }),
+                    )
                 }
-            }.into()
-        })
-    })
-             .and_then(move |body: serde_json::Value| -> EndpointFutureBox {
-                 if let Some(_auth) = body["auth"].as_object() {
-                     match query.get("kind").unwrap_or("user") {
-                         "guest" => Box::new(future::err(ErrorBody::GUEST_ACCESS_FORBIDDEN.into())),
-                         "user" => {
-                             let username = body["username"].as_str().unwrap_or("").to_owned();
-                             let is_valid_username: Regex = Regex::new("^[a-z-.=_/0-9]+$").unwrap();
-                             if !is_valid_username.is_match(&username) {
-                                 return Box::new(future::err(ErrorBody::INVALID_USERNAME.into()));
-                             }
-                             let password = body["password"].to_string();
-                             let req_device_id = body["device_id"].as_str().map(|x| x.to_owned());
-                             println!("Hashing password...");
-                             Box::new(
-                                 cpupool
-                                 .spawn_fn(move || bcrypt::hash(&password, bcrypt::DEFAULT_COST))
-                                 .map_err(crate::Error::from)
-                                 .and_then(move |hash| -> EndpointFutureBox {
-                                     println!("{:?}", hash);
-                                     Box::new(
-                                         db_pool.run(|mut db| {
-                                             db.prepare(REGISTER_QUERY)
-                                                 .then(|res| tack_on(res, db))
-                                                 .and_then(move |(q, mut db)| {
-                                                     let id = uuid::Uuid::new_v4();
-                                                     {
-                                                         let values: Vec<&dyn tokio_postgres::types::ToSql> = vec![&id, &username, &hash];
-                                                         db.execute(&q, &values)
-                                                     }.and_then(move |_| Ok((id, username)))
-                                                     .then(|res| tack_on(res, db))
-                                                 })
-                                             .and_then(
-                                                 move |((user_id, username), db)| {
-                                                     let device_id = req_device_id
-                                                         .unwrap_or_else(|| {
-                                                             generate_device_id()
-                                                         });
-                                                     create_access_token(db, user_id.clone(), device_id.clone())
-                                                         .and_then(|(token, db)| Ok(((token, device_id, username), db)))
-                                                 },
-                                                 )
-                                         })
-                                     .map_err(crate::Error::from)
-                                         .and_then(move |(token, device_id, username)| {
-                                             Ok(Response::new(Body::from(
-                                                         json!({
-                                                             "user_id": username,
-                                                             "access_token": token,
-                                                             "device_id": device_id,
-                                                             "home_server": *hostname
-                                                         }).to_string(),
-                                                         )))
-                                         }))
-                                 })
-                                 )
-                         }
-                         _ => {
-                             Box::new(future::err(
-                                     ErrorBody::new_static(
-                                         error_code::CHAT_LOMATIA_INVALID_PARAM,
-                                         "Invalid 'kind' value, must be either 'guest' or 'user'",
-                                         ).into()))
-                         }
-                     }
-                 } else {
-                     let session_id = "_session_id"; // TODO: Generate randomly
-                     let mut resp = Response::new(Body::from(
-                             json!({
-                                 "flows": [
-                                 {
-                                     "stages": [
-                                         "m.login.dummy"
-                                     ]
-                                 }
-                                 ],
-                                 "session": session_id
-                             }).to_string(),
-                             ));
-                     *resp.status_mut() = StatusCode::UNAUTHORIZED;
-                     resp.headers_mut().insert(
-                         hyper::header::CONTENT_TYPE,
-                         hyper::header::HeaderValue::from_static(APPLICATION_JSON),
-                         );
-                     Box::new(future::ok(resp))
-                 }
-             }))
+                _ => Box::new(future::err(
+                    ErrorBody::new_static(
+                        error_code::CHAT_LOMATIA_INVALID_PARAM,
+                        "Invalid 'kind' value, must be either 'guest' or 'user'",
+                    )
+                    .into(),
+                )),
+            }
+        },
+    ))
+}
+
+fn default_logout_devices() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordReqBody {
+    new_password: String,
+    #[serde(default = "default_logout_devices")]
+    logout_devices: bool,
+    auth: Option<serde_json::Value>,
+}
+
+/// Changes the calling user's password, by default also revoking every other access token they
+/// hold.
+pub fn change_password(
+    server: &LMServer,
+    auth: AuthContext,
+    req: Request<Body>,
+) -> EndpointFutureBox {
+    let server = server.clone();
+
+    Box::new(
+        parse_json_body(req.into_body()).and_then(move |body: ChangePasswordReqBody| {
+            let cpupool = server.cpupool.clone();
+            let db_pool = server.db_pool.clone();
+            let new_password = body.new_password;
+            let logout_devices = body.logout_devices;
+
+            user_interactive_auth::check(&server, PASSWORD_FLOWS, Some(auth.user_id), body.auth)
+                .and_then(move |()| {
+                    cpupool
+                        .spawn_fn(move || bcrypt::hash(&new_password, bcrypt::DEFAULT_COST))
+                        .map_err(crate::Error::from)
+                })
+                .and_then(move |hash| {
+                    db_pool
+                        .run(move |db| {
+                            db::transaction(db, move |db| {
+                                db::lift(db::execute(
+                                    db,
+                                    CHANGE_PASSWORD_QUERY,
+                                    params![auth.user_id, hash],
+                                ))
+                                .and_then(
+                                    move |(_, db)| -> db::TransactionFutureBox<()> {
+                                        if logout_devices {
+                                            Box::new(
+                                                db::lift(db::execute(
+                                                    db,
+                                                    LOGOUT_OTHER_DEVICES_QUERY,
+                                                    params![auth.user_id, auth.device_id.clone()],
+                                                ))
+                                                .and_then(move |(_, db)| {
+                                                    db::lift(db::execute(
+                                                        db,
+                                                        LOGOUT_OTHER_TOKENS_QUERY,
+                                                        params![auth.user_id, auth.token_id],
+                                                    ))
+                                                })
+                                                .map(|(_, db)| ((), db)),
+                                            )
+                                        } else {
+                                            Box::new(future::ok(((), db)))
+                                        }
+                                    },
+                                )
+                            })
+                        })
+                        .map_err(crate::Error::from)
+                })
+                .map(|()| json_response(json!({})))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct DeactivateReqBody {
+    #[serde(default)]
+    erase: bool,
+    auth: Option<serde_json::Value>,
+}
+
+/// Permanently deactivates the calling user's account and revokes all of their access tokens.
+///
+/// If `erase` is set, the account's profile is cleared and it is marked as erased, so that its
+/// data is no longer shared.
+pub fn deactivate(server: &LMServer, auth: AuthContext, req: Request<Body>) -> EndpointFutureBox {
+    let server = server.clone();
+
+    Box::new(
+        parse_json_body(req.into_body()).and_then(move |body: DeactivateReqBody| {
+            let db_pool = server.db_pool.clone();
+            let erase = body.erase;
+            let user_id = auth.user_id;
+
+            user_interactive_auth::check(&server, PASSWORD_FLOWS, Some(user_id), body.auth)
+                .and_then(move |()| {
+                    db_pool
+                        .run(move |db| {
+                            db::transaction(db, move |db| {
+                                db::lift(db::execute(db, DEACTIVATE_QUERY, params![user_id, erase]))
+                                    .and_then(move |(_, db)| {
+                                        db::lift(db::execute(
+                                            db,
+                                            LOGOUT_USER_QUERY,
+                                            params![user_id],
+                                        ))
+                                    })
+                                    .and_then(move |(_, db)| {
+                                        db::lift(db::execute(
+                                            db,
+                                            DELETE_LOGIN_TOKENS_QUERY,
+                                            params![user_id],
+                                        ))
+                                    })
+                                    .and_then(move |(_, db)| {
+                                        db::lift(db::execute(
+                                            db,
+                                            profile::ERASE_PROFILE_QUERY,
+                                            params![user_id, erase],
+                                        ))
+                                    })
+                                    .map(|(_, db)| ((), db))
+                            })
+                        })
+                        .map_err(crate::Error::from)
+                })
+                .map(|()| json_response(json!({ "id_server_unbind_result": "no-support" })))
+        }),
+    )
+}
+
+/// Returns the fully-qualified user ID and device ID that the presented access token belongs to.
+pub fn whoami(server: &LMServer, auth: AuthContext, _req: Request<Body>) -> EndpointFutureBox {
+    Box::new(future::ok(json_response(json!({
+        "user_id": UserId::new(&auth.localpart, &server.server_name).to_string(),
+        "device_id": auth.device_id,
+    }))))
 }
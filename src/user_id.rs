@@ -0,0 +1,165 @@
+use std::fmt;
+
+use crate::{error_code, ErrorBody};
+
+const INVALID_USER_ID: ErrorBody =
+    ErrorBody::new_static(error_code::M_INVALID_PARAM, "Invalid user ID");
+
+/// A fully-qualified Matrix user ID, e.g. `@alice:example.com`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UserId {
+    localpart: String,
+    server_name: String,
+}
+
+impl UserId {
+    /// Creates the ID of a user on `server_name` without validating either part.
+    pub fn new(localpart: &str, server_name: &str) -> UserId {
+        UserId {
+            localpart: localpart.to_owned(),
+            server_name: server_name.to_owned(),
+        }
+    }
+
+    /// Parses a fully-qualified user ID.
+    ///
+    /// Historical localparts which contain characters no longer allowed for new users are
+    /// accepted, as they may still belong to existing users on other servers.
+    pub fn parse(user_id: &str) -> Result<UserId, ErrorBody> {
+        if user_id.len() > 255 || !user_id.starts_with('@') {
+            return Err(INVALID_USER_ID);
+        }
+
+        let mut parts = user_id[1..].splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(localpart), Some(server_name))
+                if !localpart.is_empty()
+                    && localpart.chars().all(|c| ('!'..='~').contains(&c))
+                    && is_valid_server_name(server_name) =>
+            {
+                Ok(UserId::new(localpart, server_name))
+            }
+            _ => Err(INVALID_USER_ID),
+        }
+    }
+
+    /// Interprets `user` as either a fully-qualified user ID or a localpart on `server_name`.
+    pub fn parse_with_server_name(user: &str, server_name: &str) -> Result<UserId, ErrorBody> {
+        if user.starts_with('@') {
+            UserId::parse(user)
+        } else {
+            Ok(UserId::new(user, server_name))
+        }
+    }
+
+    pub fn localpart(&self) -> &str {
+        &self.localpart
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "@{}:{}", self.localpart, self.server_name)
+    }
+}
+
+/// Checks whether `localpart` may be used for a newly registered user.
+pub fn is_valid_localpart(localpart: &str) -> bool {
+    !localpart.is_empty()
+        && localpart
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '.' | '=' | '_' | '/'))
+}
+
+/// Checks that `server_name` is a hostname, IPv4 address or bracketed IPv6 address, optionally
+/// followed by a port.
+pub fn is_valid_server_name(server_name: &str) -> bool {
+    let (host, port) = match server_name.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((address, port)) => {
+                let valid_address = (2..=45).contains(&address.len())
+                    && address
+                        .chars()
+                        .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.');
+                if !valid_address {
+                    return false;
+                }
+                (None, port)
+            }
+            None => return false,
+        },
+        None => match server_name.find(':') {
+            Some(i) => (Some(&server_name[..i]), &server_name[i..]),
+            None => (Some(server_name), ""),
+        },
+    };
+
+    let valid_host = host.is_none_or(|host| {
+        (1..=255).contains(&host.len())
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    });
+    let valid_port = port.is_empty()
+        || port.strip_prefix(':').is_some_and(|port| {
+            (1..=5).contains(&port.len()) && port.chars().all(|c| c.is_ascii_digit())
+        });
+
+    valid_host && valid_port
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_localpart, is_valid_server_name, UserId};
+
+    #[test]
+    fn parse() {
+        let user_id = UserId::parse("@alice:example.com:8448").unwrap();
+        assert_eq!(user_id.localpart(), "alice");
+        assert_eq!(user_id.server_name(), "example.com:8448");
+        assert_eq!(user_id.to_string(), "@alice:example.com:8448");
+
+        assert!(UserId::parse("@bob:[::1]").is_ok());
+        assert!(UserId::parse("@Historical!:example.com").is_ok());
+
+        assert!(UserId::parse("alice:example.com").is_err());
+        assert!(UserId::parse("@alice").is_err());
+        assert!(UserId::parse("@:example.com").is_err());
+        assert!(UserId::parse("@alice:exa mple.com").is_err());
+        assert!(UserId::parse("@al ice:example.com").is_err());
+    }
+
+    #[test]
+    fn parse_with_server_name() {
+        let user_id = UserId::parse_with_server_name("alice", "example.com").unwrap();
+        assert_eq!(user_id.to_string(), "@alice:example.com");
+
+        let user_id = UserId::parse_with_server_name("@bob:example.org", "example.com").unwrap();
+        assert_eq!(user_id.server_name(), "example.org");
+    }
+
+    #[test]
+    fn valid_localparts() {
+        assert!(is_valid_localpart("alice.smith=1/x_y-z"));
+        assert!(!is_valid_localpart(""));
+        assert!(!is_valid_localpart("Alice"));
+        assert!(!is_valid_localpart("al ice"));
+    }
+
+    #[test]
+    fn valid_server_names() {
+        assert!(is_valid_server_name("example.com"));
+        assert!(is_valid_server_name("1.2.3.4:8448"));
+        assert!(is_valid_server_name("[1234:5678::abcd]:443"));
+        assert!(!is_valid_server_name(""));
+        assert!(!is_valid_server_name("example.com:"));
+        assert!(!is_valid_server_name("example.com:123456"));
+        assert!(!is_valid_server_name("[::1"));
+        assert!(!is_valid_server_name("[::1]x"));
+        assert!(!is_valid_server_name("exa_mple.com"));
+    }
+}
@@ -0,0 +1,291 @@
+use futures::{future, Future};
+use serde_json::{json, Value};
+
+use crate::db;
+use crate::user_id::UserId;
+use crate::{error_code, ErrorBody, LMServer};
+
+pub const DUMMY: &str = "m.login.dummy";
+pub const PASSWORD: &str = "m.login.password";
+
+/// A series of stages which together authenticate a request.
+pub type Flow = &'static [&'static str];
+
+const NEW_SESSION_QUERY: &str = "INSERT INTO uia_sessions (id, user_id, created, completed) \
+                                 VALUES ($1, $2, localtimestamp, '{}')";
+
+const EXPIRE_SESSIONS_QUERY: &str =
+    "DELETE FROM uia_sessions WHERE created < localtimestamp - interval '1 hour'";
+
+const SESSION_QUERY: &str = "SELECT user_id, completed FROM uia_sessions \
+                             WHERE id=$1 AND created >= localtimestamp - interval '1 hour'";
+
+const COMPLETE_STAGE_QUERY: &str =
+    "UPDATE uia_sessions SET completed = array_append(completed, $2) \
+     WHERE id=$1 AND NOT ($2 = ANY(completed)) RETURNING completed";
+
+const DELETE_SESSION_QUERY: &str = "DELETE FROM uia_sessions WHERE id=$1";
+
+const PASSWORD_QUERY: &str = "SELECT id, passhash FROM users WHERE localpart=$1";
+
+const UNKNOWN_SESSION: ErrorBody =
+    ErrorBody::new_static(error_code::M_UNKNOWN, "Unknown authentication session");
+const UNSUPPORTED_STAGE: ErrorBody =
+    ErrorBody::new_static(error_code::M_UNKNOWN, "Unsupported authentication stage");
+const INVALID_PASSWORD: ErrorBody =
+    ErrorBody::new_static(error_code::M_FORBIDDEN, "Invalid password");
+
+type UiaFutureBox<T> = Box<dyn Future<Item = T, Error = crate::Error> + Send>;
+
+fn generate_session_id() -> String {
+    uuid::Uuid::new_v4().to_simple().to_string()
+}
+
+/// Builds the 401 response body describing the progress of a session.
+fn progress(
+    flows: &[Flow],
+    session: &str,
+    completed: &[String],
+    error: Option<ErrorBody>,
+) -> crate::Error {
+    let flows: Vec<_> = flows
+        .iter()
+        .map(|stages| json!({ "stages": stages }))
+        .collect();
+    let mut body = json!({
+        "flows": flows,
+        "params": {},
+        "session": session,
+        "completed": completed,
+    });
+
+    if let Some(error) = error {
+        body["errcode"] = json!(error.errcode);
+        body["error"] = json!(error.error);
+    }
+
+    crate::Error::UserInteractiveAuth(body)
+}
+
+fn new_session(server: &LMServer, user_id: Option<uuid::Uuid>) -> UiaFutureBox<String> {
+    let session = generate_session_id();
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| {
+                db::execute(db, EXPIRE_SESSIONS_QUERY, params![]).and_then(move |(_, db)| {
+                    db::execute(db, NEW_SESSION_QUERY, params![session.clone(), user_id])
+                        .map(move |(_, db)| (session, db))
+                })
+            })
+            .map_err(crate::Error::from),
+    )
+}
+
+fn load_session(
+    server: &LMServer,
+    session: String,
+    user_id: Option<uuid::Uuid>,
+) -> UiaFutureBox<(String, Vec<String>)> {
+    Box::new(
+        server
+            .db_pool
+            .run({
+                let session = session.clone();
+                move |db| db::query_opt(db, SESSION_QUERY, params![session])
+            })
+            .map_err(crate::Error::from)
+            .and_then(move |row| {
+                let row = row.ok_or(UNKNOWN_SESSION)?;
+                let session_user: Option<uuid::Uuid> = row.get(0);
+
+                // A session may only be continued on behalf of the user who started it.
+                if session_user != user_id {
+                    return Err(UNKNOWN_SESSION.into());
+                }
+
+                Ok((session, row.get(1)))
+            }),
+    )
+}
+
+/// Checks the `m.login.password` stage, which must identify `user_id` if it is known.
+fn check_password(
+    server: &LMServer,
+    auth: &Value,
+    user_id: Option<uuid::Uuid>,
+) -> UiaFutureBox<()> {
+    let user = match auth["identifier"]["type"].as_str() {
+        Some("m.id.user") => auth["identifier"]["user"].as_str(),
+        Some(_) => {
+            return Box::new(future::err(
+                ErrorBody::new_static(error_code::M_UNKNOWN, "3pid is not supported").into(),
+            ))
+        }
+        None => auth["user"].as_str(),
+    };
+    let (user, password) = match (user, auth["password"].as_str()) {
+        (Some(user), Some(password)) => (user, password.to_owned()),
+        _ => {
+            return Box::new(future::err(
+                ErrorBody::new_static(error_code::M_MISSING_PARAM, "Missing user or password")
+                    .into(),
+            ))
+        }
+    };
+
+    let localpart = match UserId::parse_with_server_name(user, &server.server_name) {
+        Ok(ref user) if user.server_name() == server.server_name.as_str() => {
+            user.localpart().to_owned()
+        }
+        Ok(_) => return Box::new(future::err(INVALID_PASSWORD.into())),
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    let cpupool = server.cpupool.clone();
+    Box::new(
+        server
+            .db_pool
+            .run(move |db| db::query_opt(db, PASSWORD_QUERY, params![localpart]))
+            .map_err(crate::Error::from)
+            .and_then(move |row| {
+                let row = row.ok_or(INVALID_PASSWORD)?;
+                let found_user_id: uuid::Uuid = row.get(0);
+
+                if user_id.is_some_and(|user_id| user_id != found_user_id) {
+                    return Err(INVALID_PASSWORD.into());
+                }
+
+                Ok(row.get::<_, String>(1))
+            })
+            .and_then(move |passhash| {
+                cpupool
+                    .spawn_fn(move || bcrypt::verify(password, &passhash))
+                    .map_err(crate::Error::from)
+            })
+            .and_then(|correct| {
+                if correct {
+                    Ok(())
+                } else {
+                    Err(INVALID_PASSWORD.into())
+                }
+            }),
+    )
+}
+
+fn check_stage(
+    server: &LMServer,
+    stage: &str,
+    auth: &Value,
+    user_id: Option<uuid::Uuid>,
+) -> UiaFutureBox<()> {
+    match stage {
+        DUMMY => Box::new(future::ok(())),
+        PASSWORD => check_password(server, auth, user_id),
+        _ => Box::new(future::err(UNSUPPORTED_STAGE.into())),
+    }
+}
+
+/// Runs user-interactive authentication for a request.
+///
+/// `auth` is the `auth` dict from the request body, and `user_id` is the user the request acts on
+/// behalf of, if one is known. The returned future only succeeds once every stage of one of
+/// `flows` has been completed; until then it fails with the session's progress, which is sent to
+/// the client with a 401 status.
+pub fn check(
+    server: &LMServer,
+    flows: &'static [Flow],
+    user_id: Option<uuid::Uuid>,
+    auth: Option<Value>,
+) -> UiaFutureBox<()> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            return Box::new(
+                new_session(server, user_id)
+                    .and_then(move |session| Err(progress(flows, &session, &[], None))),
+            )
+        }
+    };
+
+    let session: UiaFutureBox<_> = match auth["session"].as_str() {
+        Some(session) => load_session(server, session.to_owned(), user_id),
+        None => Box::new(new_session(server, user_id).map(|session| (session, Vec::new()))),
+    };
+
+    let server = server.clone();
+    Box::new(
+        session.and_then(move |(session, completed)| -> UiaFutureBox<()> {
+            let stage = match auth["type"].as_str() {
+                Some(stage) => stage.to_owned(),
+                None => return Box::new(future::err(progress(flows, &session, &completed, None))),
+            };
+            if !flows.iter().any(|stages| stages.contains(&stage.as_str())) {
+                return Box::new(future::err(progress(
+                    flows,
+                    &session,
+                    &completed,
+                    Some(UNSUPPORTED_STAGE),
+                )));
+            }
+
+            let db_pool = server.db_pool.clone();
+            Box::new(check_stage(&server, &stage, &auth, user_id).then(
+                move |res| -> UiaFutureBox<()> {
+                    match res {
+                        Ok(()) => Box::new(
+                            db_pool
+                                .run({
+                                    let session = session.clone();
+                                    move |db| {
+                                        db::query_opt(
+                                            db,
+                                            COMPLETE_STAGE_QUERY,
+                                            params![session, stage],
+                                        )
+                                    }
+                                })
+                                .map_err(crate::Error::from)
+                                .and_then(move |row| -> UiaFutureBox<()> {
+                                    // The stage may already have been recorded as complete.
+                                    let completed: Vec<String> =
+                                        row.map(|row| row.get(0)).unwrap_or(completed);
+
+                                    let done = flows.iter().any(|stages| {
+                                        stages
+                                            .iter()
+                                            .all(|stage| completed.iter().any(|done| done == stage))
+                                    });
+                                    if !done {
+                                        return Box::new(future::err(progress(
+                                            flows, &session, &completed, None,
+                                        )));
+                                    }
+
+                                    Box::new(
+                                        db_pool
+                                            .run(move |db| {
+                                                db::execute(
+                                                    db,
+                                                    DELETE_SESSION_QUERY,
+                                                    params![session],
+                                                )
+                                            })
+                                            .map_err(crate::Error::from)
+                                            .map(|_| ()),
+                                    )
+                                }),
+                        ),
+                        Err(crate::Error::UserFacing(err)) => Box::new(future::err(progress(
+                            flows,
+                            &session,
+                            &completed,
+                            Some(err),
+                        ))),
+                        Err(err) => Box::new(future::err(err)),
+                    }
+                },
+            ))
+        }),
+    )
+}